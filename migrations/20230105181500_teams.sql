@@ -0,0 +1,4 @@
+-- Add migration script here
+alter table "game_state"
+    add column team_id   uuid not null default '00000000-0000-0000-0000-000000000000',
+    add column team_name text not null default '';
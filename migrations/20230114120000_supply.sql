@@ -0,0 +1,4 @@
+-- Add migration script here
+alter table "game_state"
+    add column supply_ordered BIGINT not null default 0,
+    add column supply_delivered BIGINT not null default 0;
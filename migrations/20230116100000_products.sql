@@ -0,0 +1,3 @@
+-- Add migration script here
+alter table "game_state"
+    add column products JSONB not null default '{}';
@@ -0,0 +1,3 @@
+-- Add migration script here
+alter table "lobby"
+    add column paused BOOLEAN not null default false;
@@ -0,0 +1,4 @@
+-- Add migration script here
+alter table "game_state"
+    add column disruptions JSONB not null default '[]',
+    add column delayed_shipments JSONB not null default '[]';
@@ -0,0 +1,3 @@
+-- Add migration script here
+alter table "game_state"
+    add column settings JSONB;
@@ -116,7 +116,10 @@ pub async fn create_test_lobbies(
         generate_connect_code: false,
         code_use_times: 0,
         max_players: 3,
-        settings: Some(Settings::default()),
+        settings: Some(Settings {
+            max_rounds: 10,
+            ..Settings::default()
+        }),
         events: Some(GameEvents::new()),
     };
 
@@ -141,7 +144,10 @@ pub async fn create_test_lobbies(
         generate_connect_code: true,
         code_use_times: 2,
         max_players: 3,
-        settings: Some(Settings::default()),
+        settings: Some(Settings {
+            max_rounds: 10,
+            ..Settings::default()
+        }),
         events: Some(GameEvents::new()),
     };
 
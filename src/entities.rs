@@ -1,6 +1,10 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    hash::{Hash, Hasher},
+};
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use sqlx::{
     types::{Json, Uuid},
     FromRow,
@@ -36,6 +40,7 @@ pub struct Lobby {
     pub code_use_times: i16,
     pub max_players: i16,
     pub started: bool,
+    pub paused: bool,
     pub owner_id: Uuid,
     pub settings: Json<Settings>,
     pub events: Json<GameEvents>,
@@ -48,6 +53,12 @@ pub struct Settings {
     pub user_classes: Vec<u32>,
     pub incoming_start_queue: BTreeMap<u32, Vec<i64>>,
     pub requested_start_queue: BTreeMap<u32, Vec<i64>>,
+    /// Rounds between shipping goods and a player of the class receiving them.
+    #[serde(default)]
+    pub shipping_delay: BTreeMap<u32, usize>,
+    /// Rounds between placing an order and a player of the class processing it.
+    #[serde(default)]
+    pub order_delay: BTreeMap<u32, usize>,
     pub demand_style: GeneratedOrderStyle,
     pub supply_style: GeneratedOrderStyle,
     pub unlimited_money: bool,
@@ -60,6 +71,189 @@ pub struct Settings {
     pub fix_order_cost: BTreeMap<u32, i64>,
     pub back_order_cost: BTreeMap<u32, i64>,
     pub additional_cost: BTreeMap<u32, i64>,
+    #[serde(default)]
+    pub bankruptcy_policy: BankruptcyPolicy,
+    #[serde(default)]
+    pub performance_metric: PerformanceMetric,
+    /// Seconds players have to place an order, rounds are not timed without it.
+    #[serde(default)]
+    pub round_time_limit: Option<u64>,
+    /// Order placed for players that didn't make it before the time limit.
+    #[serde(default)]
+    pub default_order: DefaultOrderPolicy,
+    /// Units a player of the class can manufacture per round. Chain sources of the
+    /// listed classes produce goods from the supply instead of relaying it.
+    #[serde(default)]
+    pub production_capacity: BTreeMap<u32, i64>,
+    /// Rounds between starting production and the goods reaching the magazine.
+    #[serde(default)]
+    pub production_lead_time: BTreeMap<u32, usize>,
+    /// Cost of every manufactured unit.
+    #[serde(default)]
+    pub production_cost: BTreeMap<u32, i64>,
+    /// Units a player of the class can keep in the magazine, unlimited when missing.
+    #[serde(default)]
+    pub warehouse_capacity: BTreeMap<u32, i64>,
+    #[serde(default)]
+    pub warehouse_overflow: WarehouseOverflow,
+    /// Percent of the stock that goes bad every round.
+    #[serde(default)]
+    pub spoilage_rate: BTreeMap<u32, i64>,
+    /// Products sold next to the main one, sharing the players' money and warehouse.
+    #[serde(default)]
+    pub products: Vec<Product>,
+    #[serde(default)]
+    pub visibility: Visibility,
+}
+
+impl Settings {
+    /// Settings with a JSON merge patch applied: fields missing from the patch keep
+    /// their value, `null` removes optional ones.
+    pub fn patched(&self, patch: &SettingsPatch) -> Result<Settings, AppError> {
+        let mut settings =
+            serde_json::to_value(self).map_err(|e| AppError::InternalServerError(e.to_string()))?;
+        merge_patch(&mut settings, &patch.0);
+        let settings: Settings = serde_json::from_value(settings)
+            .map_err(|e| AppError::BadRequest(format!("bad settings patch: {}", e)))?;
+        settings.validate()?;
+
+        Ok(settings)
+    }
+
+    /// Rejects settings a game can't be played with.
+    pub fn validate(&self) -> Result<(), AppError> {
+        if self.max_rounds <= 0 {
+            return Err(AppError::BadRequest(format!(
+                "max rounds of {} is not positive",
+                self.max_rounds
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let patch = match patch {
+        Value::Object(p) => p,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(serde_json::Map::new());
+    }
+    if let Value::Object(target) = target {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+/// JSON merge patch (RFC 7386) of the game settings.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SettingsPatch(pub Value);
+
+impl Hash for SettingsPatch {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_string().hash(state);
+    }
+}
+
+/// Additional product of a game with its own prices, demand and supply. Delays,
+/// the other costs and the production setup are shared with the main product.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct Product {
+    pub name: String,
+    pub demand_style: GeneratedOrderStyle,
+    pub supply_style: GeneratedOrderStyle,
+    pub resource_basic_price: i64,
+    pub resource_price: BTreeMap<u32, i64>,
+    pub start_magazine: BTreeMap<u32, i64>,
+    pub magazine_cost: BTreeMap<u32, i64>,
+}
+
+/// Demand and supply generator state of an additional product.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct ProductRound {
+    pub demand: i64,
+    pub supply: Supply,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Hash)]
+#[serde(tag = "type")]
+pub enum DefaultOrderPolicy {
+    /// Repeats the order placed in the previous round.
+    #[default]
+    LastOrder,
+    Zero,
+    Fixed {
+        value: i64,
+    },
+    /// Orders as much as the player was asked for.
+    PassThrough,
+}
+
+/// How much of the other players the round updates show.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Hash)]
+#[serde(tag = "type")]
+pub enum Visibility {
+    #[default]
+    Full,
+    /// The player, its senders and its customers.
+    Neighbors,
+    Own,
+    /// The player and the customer demand.
+    PointOfSale,
+}
+
+/// What happens to stock above the warehouse capacity at the end of a round.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Hash)]
+#[serde(tag = "type")]
+pub enum WarehouseOverflow {
+    /// The excess is thrown away.
+    #[default]
+    Reject,
+    /// The excess is kept for `cost` per unit on top of the magazine cost.
+    Surcharge { cost: i64 },
+}
+
+/// How `UserState.performance` is computed each round.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub enum PerformanceMetric {
+    /// Percent of all requested units that were shipped.
+    #[default]
+    FillRate,
+    /// Percent of rounds in which everything requested, backlog included, was shipped.
+    ServiceLevel,
+    /// Money spent per shipped unit.
+    CostPerUnit,
+}
+
+/// What happens to a player that can't pay, ignored with `unlimited_money`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Hash)]
+#[serde(tag = "type")]
+pub enum BankruptcyPolicy {
+    /// Orders costing more than the player has are rejected.
+    #[default]
+    RejectOrder,
+    /// Money can go below zero, `interest_rate` percent of the debt is charged each round.
+    NegativeBalance { interest_rate: i64 },
+    /// A player ending a round with negative money is replaced by a stand-in.
+    Eliminate,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub enum BankruptcyOutcome {
+    OrderRejected,
+    NegativeBalance,
+    Eliminated,
 }
 
 #[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Hash)]
@@ -80,9 +274,70 @@ pub enum GeneratedOrderStyle {
         power: i64,
         modulator: i64,
     },
+    /// Value of the round in `list`, `after_end` decides what happens past its end.
     List {
         list: Vec<i64>,
+        #[serde(default)]
+        after_end: ListEnd,
+    },
+    /// Normally distributed, rounded and never below zero.
+    Normal {
+        mean: i64,
+        std_dev: i64,
+    },
+    Poisson {
+        mean: i64,
+    },
+    /// Uniform between `min` and `max`, both included.
+    Uniform {
+        min: i64,
+        max: i64,
+    },
+    /// Jumps from `before` to `after` in round `round`, with normal noise.
+    StepChange {
+        before: i64,
+        after: i64,
+        round: i64,
+        noise: i64,
+    },
+    /// `base` plus a sine wave repeating every `period` rounds.
+    Seasonal {
+        base: i64,
+        amplitude: i64,
+        period: i64,
     },
+    /// `base` raised by each promotion while it runs.
+    Promotion {
+        base: i64,
+        promotions: Vec<DemandPromotion>,
+    },
+    /// Constant value per range of rounds, the last one is kept after its end.
+    Piecewise {
+        segments: Vec<DemandSegment>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub enum ListEnd {
+    #[default]
+    RepeatLast,
+    Cycle,
+    EndGame,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct DemandPromotion {
+    pub from: i64,
+    pub to: i64,
+    pub increase: i64,
+}
+
+/// Rounds `from` to `to`, both included.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct DemandSegment {
+    pub from: i64,
+    pub to: i64,
+    pub value: i64,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
@@ -94,6 +349,54 @@ impl GameEvents {
     pub fn new() -> Self {
         Self { events: Vec::new() }
     }
+
+    /// Rejects actions the engine couldn't apply to a game played with `settings`.
+    pub fn validate(&self, settings: &Settings) -> Result<(), AppError> {
+        let actions = || self.events.iter().flat_map(|e| &e.actions);
+
+        // products can also come from settings changed by an event
+        let mut products: BTreeSet<String> =
+            settings.products.iter().map(|p| p.name.clone()).collect();
+        for action in actions() {
+            let changed = match action {
+                EventAction::ChangeSettings { new_settings } => {
+                    new_settings.validate()?;
+                    new_settings.clone()
+                }
+                EventAction::PatchSettings { patch } => settings.patched(patch)?,
+                _ => continue,
+            };
+            products.extend(changed.products.into_iter().map(|p| p.name));
+        }
+
+        for action in actions() {
+            match action {
+                EventAction::CutProductionCapacity { percent, .. }
+                    if !(0..=100).contains(percent) =>
+                {
+                    return Err(AppError::BadRequest(format!(
+                        "production cut of {}% is not between 0 and 100",
+                        percent
+                    )));
+                }
+                EventAction::ScaleDemand { percent, .. } if *percent < 0 => {
+                    return Err(AppError::BadRequest(format!(
+                        "demand can't be scaled by {}%",
+                        percent
+                    )));
+                }
+                EventAction::ChangePrice {
+                    product: Some(name),
+                    ..
+                } if !products.contains(name) => {
+                    return Err(AppError::BadRequest(format!("unknown product {}", name)));
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
@@ -101,27 +404,112 @@ pub struct GameEvent {
     pub name: String,
     pub condition: EventCondition,
     pub actions: Vec<EventAction>,
-    // pub recipients: EventsRecipients,
     pub run_once: bool,
+    /// Checked only every that many rounds, counted from `from_round`.
+    #[serde(default)]
+    pub every: Option<i64>,
+    /// First round the event is checked in.
+    #[serde(default)]
+    pub from_round: Option<i64>,
+    /// Last round the event is checked in.
+    #[serde(default)]
+    pub to_round: Option<i64>,
+    /// Rounds the event is not checked in after it fired.
+    #[serde(default)]
+    pub cooldown: Option<i64>,
+}
+
+impl GameEvent {
+    /// Whether the event is checked in `round`, `fired` are the rounds it already
+    /// fired in.
+    pub fn is_active(&self, round: i64, fired: &[i64]) -> bool {
+        if self.run_once && !fired.is_empty() {
+            return false;
+        }
+
+        let from = self.from_round.unwrap_or(0);
+        if round < from || matches!(self.to_round, Some(to) if round > to) {
+            return false;
+        }
+        if let Some(every) = self.every.filter(|every| *every > 0) {
+            if (round - from) % every != 0 {
+                return false;
+            }
+        }
+
+        match (self.cooldown, fired.last()) {
+            (Some(cooldown), Some(last)) => round - last > cooldown,
+            _ => true,
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
 #[serde(tag = "type")]
 pub enum EventCondition {
+    /// Without a comparison the round has to be equal.
     RoundMet {
         round: i64,
+        #[serde(default)]
+        compare: Option<Comparison>,
     },
+    /// Without a comparison the resource has to be greater, or greater or equal
+    /// for all players.
     ValueExceed {
         resource: Resource,
         met_by: MetBy,
         value: i64,
+        #[serde(default)]
+        compare: Option<Comparison>,
     },
+    /// Compares the absolute change since the last round, greater by default.
     SingleChange {
         resource: Resource,
         value: i64,
+        #[serde(default)]
+        compare: Option<Comparison>,
+    },
+    /// Met by the players meeting every condition.
+    And { conditions: Vec<EventCondition> },
+    /// Met by the players meeting any of the conditions.
+    Or { conditions: Vec<EventCondition> },
+    /// Met by the players not meeting the condition.
+    Not { condition: Box<EventCondition> },
+    /// The condition checked only for a group of players, averages are taken
+    /// over the group too.
+    Within {
+        players: PlayerGroup,
+        condition: Box<EventCondition>,
     },
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub enum Comparison {
+    #[serde(alias = ">")]
+    Greater,
+    #[serde(alias = ">=")]
+    GreaterOrEqual,
+    #[serde(alias = "<")]
+    Less,
+    #[serde(alias = "<=")]
+    LessOrEqual,
+    #[serde(alias = "==")]
+    Equal,
+}
+
+impl Comparison {
+    /// If `left` compared to `right` holds.
+    pub fn holds(&self, left: i64, right: i64) -> bool {
+        match self {
+            Comparison::Greater => left > right,
+            Comparison::GreaterOrEqual => left >= right,
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Equal => left == right,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
 pub enum Resource {
     Money,
@@ -140,11 +528,92 @@ pub enum EventAction {
     ChangeSettings {
         new_settings: Settings,
     },
+    /// Changes only the settings fields given in the patch.
+    PatchSettings {
+        patch: SettingsPatch,
+    },
     AddResource {
         resource: Resource,
         target: ActionTarget,
         value: i64,
     },
+    /// Sets how much players of the class can manufacture per round.
+    ChangeProductionCapacity {
+        class: u32,
+        capacity: i64,
+    },
+    /// Nothing is shipped on the link for `rounds` rounds, the goods wait at the
+    /// sender as back orders.
+    BlockShipments {
+        link: ClassLink,
+        rounds: i64,
+    },
+    /// Goods shipped on the link in the next `rounds` rounds arrive `delay` rounds
+    /// later.
+    DelayShipments {
+        link: ClassLink,
+        delay: i64,
+        rounds: i64,
+    },
+    /// Customer demand generated in the next `rounds` rounds is scaled by `percent`.
+    ScaleDemand {
+        percent: i64,
+        rounds: i64,
+    },
+    /// Lowers the production capacity of the class by `percent` for `rounds` rounds.
+    CutProductionCapacity {
+        class: u32,
+        percent: i64,
+        rounds: i64,
+    },
+    /// Sets the price of a product for players of the class, the main product
+    /// when no name is given.
+    ChangePrice {
+        class: u32,
+        #[serde(default)]
+        product: Option<String>,
+        price: i64,
+    },
+    /// Adds units to the customer demand generated for the current round, once.
+    DemandShock {
+        value: i64,
+    },
+}
+
+/// Links between the players of two classes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct ClassLink {
+    pub sender: u32,
+    pub recipient: u32,
+}
+
+/// Event action lasting for several rounds, started in round `start`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct Disruption {
+    pub start: i64,
+    pub action: EventAction,
+}
+
+impl Disruption {
+    pub fn is_active(&self, round: i64) -> bool {
+        let rounds = match self.action {
+            EventAction::BlockShipments { rounds, .. }
+            | EventAction::DelayShipments { rounds, .. }
+            | EventAction::ScaleDemand { rounds, .. }
+            | EventAction::CutProductionCapacity { rounds, .. } => rounds,
+            _ => 0,
+        };
+        self.start <= round && round < self.start + rounds
+    }
+}
+
+/// Goods held back on a delayed link, they are shipped with the sender's delivery
+/// from round `release` on.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct DelayedShipment {
+    pub release: i64,
+    pub product: Option<String>,
+    pub order: Order,
 }
 
 //TODO: refactor name
@@ -155,11 +624,25 @@ pub enum MetBy {
     AllPlayers,
 }
 
+/// Players of a chain picked by their class, position or team.
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
-pub enum EventsRecipients {
-    SinglePlayer, //not possible to use with
-    PlayerMetConditions,
-    AllPlayers,
+pub enum PlayerGroup {
+    Class(u32),
+    Position(ChainPosition),
+    /// Every player when the chain is the team with this name.
+    Team(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub enum ChainPosition {
+    /// Players selling to the customer demand.
+    Retailer,
+    /// Players getting goods from the supply.
+    Factory,
+    /// Senders of the event targets, or of the checked players in a condition.
+    Upstream,
+    /// Recipients of the event targets, or of the checked players in a condition.
+    Downstream,
 }
 
 //TODO: refactor name
@@ -167,6 +650,7 @@ pub enum EventsRecipients {
 pub enum ActionTarget {
     EventTarget,
     AllPlayers,
+    Players(PlayerGroup),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash, FromRow)]
@@ -181,45 +665,225 @@ pub struct GameState {
     pub demand: i64,
     pub supply: i64,
     pub game_id: Uuid,
+    pub team_id: Uuid,
+    pub team_name: String,
+    pub seed: i64,
+    pub supply_ordered: i64,
+    pub supply_delivered: i64,
+    pub products: Json<BTreeMap<String, ProductRound>>,
+    pub fired_events: Json<BTreeMap<usize, Vec<i64>>>,
+    pub disruptions: Json<Vec<Disruption>>,
+    pub delayed_shipments: Json<Vec<DelayedShipment>>,
+    pub settings: Option<Json<Settings>>,
+}
+
+/// Raw material available to the chain sources. `capacity` is generated by the
+/// supply style every round and shared by all sources, orders above it are cut.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct Supply {
+    pub capacity: i64,
+    pub ordered: i64,
+    pub delivered: i64,
+}
+
+impl Supply {
+    pub fn new(capacity: i64) -> Self {
+        Self {
+            capacity,
+            ordered: 0,
+            delivered: 0,
+        }
+    }
+
+    pub fn shortage(&self) -> i64 {
+        (self.ordered - self.delivered).max(0)
+    }
 }
 
+/// One of the supply chains played in a lobby. Lobbies with a single chain use
+/// the default team with a nil id.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct Team {
+    pub id: Uuid,
+    pub name: String,
+}
+
+/// Supply chain graph. Goods go from `sender` to `recipient` of a link, orders the
+/// other way. Players without senders get goods from the supply, players without
+/// recipients sell to the customer demand.
 #[derive(Clone, Debug, PartialEq, Default, Eq, Serialize, Deserialize, Hash)]
+#[serde(from = "FlowRepr")]
 pub struct Flow {
-    pub last_player: Uuid,
-    pub first_player: Uuid,
-    pub flow: BTreeMap<Uuid, Uuid>,
+    pub links: Vec<FlowLink>,
+    pub split: OrderSplit,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct FlowLink {
+    pub sender: Uuid,
+    pub recipient: Uuid,
+    #[serde(default = "default_link_weight")]
+    pub weight: i64,
+}
+
+fn default_link_weight() -> i64 {
+    1
+}
+
+/// How an order of a player with several senders is divided between them.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub enum OrderSplit {
+    #[default]
+    Equal,
+    Weighted,
+}
+
+/// Accepts both the graph and the old chain format of stored flows.
+#[derive(Deserialize)]
+struct FlowRepr {
+    #[serde(default)]
+    links: Vec<FlowLink>,
+    #[serde(default)]
+    split: OrderSplit,
+    #[serde(default)]
+    flow: BTreeMap<Uuid, Uuid>,
+}
+
+impl From<FlowRepr> for Flow {
+    fn from(repr: FlowRepr) -> Self {
+        let mut links = repr.links;
+        if links.is_empty() {
+            links = repr
+                .flow
+                .into_iter()
+                .filter(|(_, recipient)| !recipient.is_nil())
+                .map(|(sender, recipient)| FlowLink {
+                    sender,
+                    recipient,
+                    weight: default_link_weight(),
+                })
+                .collect();
+        }
+
+        Flow {
+            links,
+            split: repr.split,
+        }
+    }
 }
 
 impl Flow {
-    pub fn get_recipient(&self, player: &Uuid) -> Result<Uuid, AppError> {
-        if *player == self.last_player {
-            return Ok(Uuid::nil());
+    /// Linear chain, every player supplies the next one.
+    pub fn chain(players: &[Uuid]) -> Self {
+        let links = players
+            .windows(2)
+            .map(|pair| FlowLink {
+                sender: pair[0],
+                recipient: pair[1],
+                weight: default_link_weight(),
+            })
+            .collect();
+
+        Flow {
+            links,
+            split: OrderSplit::Equal,
+        }
+    }
+
+    pub fn get_recipients(&self, player: &Uuid) -> Vec<Uuid> {
+        self.links
+            .iter()
+            .filter(|l| l.sender == *player)
+            .map(|l| l.recipient)
+            .collect()
+    }
+
+    /// Senders of the player with the share of its orders each one gets, a nil
+    /// sender stands for the supply.
+    pub fn get_senders_weights(&self, player: &Uuid) -> Vec<(Uuid, i64)> {
+        let senders: Vec<(Uuid, i64)> = self
+            .links
+            .iter()
+            .filter(|l| l.recipient == *player)
+            .map(|l| match self.split {
+                OrderSplit::Equal => (l.sender, 1),
+                OrderSplit::Weighted => (l.sender, l.weight),
+            })
+            .collect();
+
+        if senders.is_empty() {
+            return vec![(Uuid::nil(), 1)];
+        }
+        senders
+    }
+
+    /// Recipients of the player, a nil recipient stands for the customer demand.
+    pub fn get_customers(&self, player: &Uuid) -> Vec<Uuid> {
+        let recipients = self.get_recipients(player);
+        if recipients.is_empty() {
+            return vec![Uuid::nil()];
         }
+        recipients
+    }
+
+    pub fn is_source(&self, player: &Uuid) -> bool {
+        !self.links.iter().any(|l| l.recipient == *player)
+    }
 
-        match self.flow.get(player) {
-            Some(r) => return Ok(*r),
-            None => {
+    pub fn is_sink(&self, player: &Uuid) -> bool {
+        !self.links.iter().any(|l| l.sender == *player)
+    }
+
+    pub fn validate(&self, players: &[Uuid]) -> Result<(), AppError> {
+        let known: BTreeSet<Uuid> = players.iter().copied().collect();
+        let mut seen = BTreeSet::new();
+
+        for link in &self.links {
+            if !known.contains(&link.sender) || !known.contains(&link.recipient) {
+                return Err(AppError::BadRequest(
+                    "flow link between players not in the game".to_string(),
+                ));
+            }
+            if link.sender == link.recipient {
+                return Err(AppError::BadRequest(
+                    "player can't supply himself".to_string(),
+                ));
+            }
+            if link.weight <= 0 {
                 return Err(AppError::BadRequest(
-                    "not found recipient in flow".to_string(),
-                ))
+                    "flow link weight has to be positive".to_string(),
+                ));
+            }
+            if !seen.insert((link.sender, link.recipient)) {
+                return Err(AppError::BadRequest("duplicated flow link".to_string()));
             }
         }
-    }
 
-    pub fn get_sender(&self, player: &Uuid) -> Result<Uuid, AppError> {
-        tracing::debug!("getting sender: {} from flow: {:?}", player, self.flow);
-        for (sender, recipient) in &self.flow {
-            tracing::debug!("{}, {}", sender, recipient);
-            if *recipient == *player {
-                return Ok(*sender);
+        // remove players without senders until nothing is left, otherwise there is a cycle
+        let mut remaining: Vec<&FlowLink> = self.links.iter().collect();
+        let mut left = known;
+        loop {
+            let sources: Vec<Uuid> = left
+                .iter()
+                .filter(|p| !remaining.iter().any(|l| l.recipient == **p))
+                .copied()
+                .collect();
+            if sources.is_empty() {
+                break;
+            }
+            for source in &sources {
+                left.remove(source);
             }
+            remaining.retain(|l| !sources.contains(&l.sender));
         }
 
-        if self.first_player == *player {
-            return Ok(Uuid::nil());
+        if !left.is_empty() {
+            return Err(AppError::BadRequest(
+                "flow can't contain cycles".to_string(),
+            ));
         }
 
-        return Err(AppError::BadRequest("not found".to_string()));
+        Ok(())
     }
 }
 
@@ -236,14 +900,142 @@ pub struct UserState {
     pub user_id: Uuid,
     pub money: i64,
     pub spent_money: i64,
-    pub magazine_state: i64,
     pub performance: i64,
+    /// Stock of the main product, the one configured directly in `Settings`.
+    #[serde(flatten)]
+    pub stock: Stock,
+    #[serde(default)]
+    pub round_costs: RoundCosts,
+    #[serde(default)]
+    pub eliminated: bool,
+    #[serde(default)]
+    pub performance_stats: PerformanceStats,
+    /// Set when the position is played by a bot.
+    #[serde(default)]
+    pub bot: Option<Bot>,
+    /// Stock of the other products of the game, by name.
+    #[serde(default)]
+    pub products: BTreeMap<String, Stock>,
+}
+
+impl UserState {
+    /// Stock of `product`, the main product for `None`.
+    pub fn product_stock(&self, product: Option<&str>) -> Option<&Stock> {
+        match product {
+            Some(p) => self.products.get(p),
+            None => Some(&self.stock),
+        }
+    }
+
+    pub fn product_stock_mut(&mut self, product: Option<&str>) -> Option<&mut Stock> {
+        match product {
+            Some(p) => self.products.get_mut(p),
+            None => Some(&mut self.stock),
+        }
+    }
+
+    /// Stocks of all products, the main one first.
+    pub fn stocks(&self) -> impl Iterator<Item = &Stock> {
+        std::iter::once(&self.stock).chain(self.products.values())
+    }
+
+    pub fn stocks_mut(&mut self) -> impl Iterator<Item = &mut Stock> {
+        std::iter::once(&mut self.stock).chain(self.products.values_mut())
+    }
+}
+
+/// Magazine and order queues of a player for a single product.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct Stock {
+    pub magazine_state: i64,
     pub back_order_sum: i64,
     pub incoming_orders: Vec<Order>,
     pub requested_orders: Vec<Order>,
     pub sent_orders: Vec<Order>,
     pub placed_order: Order,
     pub received_order: Order,
+    /// Back orders of every recipient, nil for the customer demand.
+    #[serde(default)]
+    pub back_orders: BTreeMap<Uuid, i64>,
+    /// Batches being manufactured, oldest first.
+    #[serde(default)]
+    pub production: Vec<i64>,
+    /// Units thrown away last round for lack of warehouse space.
+    #[serde(default)]
+    pub rejected: i64,
+    /// Units that went bad last round.
+    #[serde(default)]
+    pub spoiled: i64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct Bot {
+    pub policy: BotPolicy,
+    /// Demand forecast kept between rounds by policies that need one.
+    #[serde(default)]
+    pub expected_demand: i64,
+}
+
+/// Ordering rule of a bot, percentages are given as whole numbers.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
+#[serde(tag = "type")]
+pub enum BotPolicy {
+    /// Orders what was requested from it.
+    PassThrough,
+    /// Orders up to `target` units of inventory position.
+    BaseStock {
+        target: i64,
+    },
+    /// Sterman's anchoring and adjustment heuristic: a smoothed demand forecast
+    /// corrected by the stock and supply line gaps.
+    AnchorAndAdjust {
+        smoothing: i64,
+        target_stock: i64,
+        stock_adjustment: i64,
+        supply_line_weight: i64,
+    },
+    Random {
+        min: i64,
+        max: i64,
+    },
+}
+
+/// Running totals the performance metric is computed from.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct PerformanceStats {
+    pub requested: i64,
+    pub shipped: i64,
+    pub rounds: i64,
+    pub rounds_served: i64,
+}
+
+/// Costs charged to a player in the last round, split by their source.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, Hash)]
+pub struct RoundCosts {
+    pub order_cost: i64,
+    pub magazine_cost: i64,
+    pub back_order_cost: i64,
+    pub transport_cost: i64,
+    pub additional_cost: i64,
+    #[serde(default)]
+    pub interest_cost: i64,
+    #[serde(default)]
+    pub production_cost: i64,
+    #[serde(default)]
+    pub surcharge_cost: i64,
+}
+
+impl RoundCosts {
+    pub fn total(&self) -> i64 {
+        self.order_cost
+            + self.magazine_cost
+            + self.back_order_cost
+            + self.transport_cost
+            + self.additional_cost
+            + self.interest_cost
+            + self.production_cost
+            + self.surcharge_cost
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, Hash)]
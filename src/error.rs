@@ -27,6 +27,7 @@ pub enum AppError {
     BadRequest(String),
     GameStarted(String),
     GameNotStarted(String),
+    GamePaused(String),
     EmptyData(String),
     BadOrder(String),
 }
@@ -67,6 +68,7 @@ impl AppError {
             AppError::GameNotStarted(s) => {
                 (StatusCode::BAD_REQUEST, format!("game not started: {}", s))
             }
+            AppError::GamePaused(s) => (StatusCode::BAD_REQUEST, format!("game paused: {}", s)),
             AppError::BadOrder(s) => (StatusCode::BAD_REQUEST, format!("Bad error: {}", s)),
         }
     }
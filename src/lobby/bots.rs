@@ -0,0 +1,105 @@
+use rand::Rng;
+
+use crate::entities::{Bot, BotPolicy, Stock};
+
+/// What a bot knows when deciding on its order.
+pub struct BotView<'a> {
+    pub stock: &'a Stock,
+    /// Requested from the player this round, not yet shipped.
+    pub requested: i64,
+}
+
+impl BotView<'_> {
+    /// Goods ordered but not delivered yet.
+    pub fn supply_line(&self) -> i64 {
+        self.stock.incoming_orders.iter().map(|o| o.value).sum()
+    }
+
+    /// Stock left after serving the backlog and this round's requests.
+    pub fn net_stock(&self) -> i64 {
+        self.stock.magazine_state - self.stock.back_order_sum - self.requested
+    }
+}
+
+pub trait OrderPolicy {
+    /// Order value for the round, never negative.
+    fn order(&mut self, view: &BotView) -> i64;
+}
+
+pub struct PassThrough;
+
+impl OrderPolicy for PassThrough {
+    fn order(&mut self, view: &BotView) -> i64 {
+        view.requested.max(0)
+    }
+}
+
+pub struct BaseStock {
+    pub target: i64,
+}
+
+impl OrderPolicy for BaseStock {
+    fn order(&mut self, view: &BotView) -> i64 {
+        let inventory_position = view.net_stock() + view.supply_line();
+        (self.target - inventory_position).max(0)
+    }
+}
+
+/// Sterman (1989) ordering heuristic.
+pub struct AnchorAndAdjust<'a> {
+    pub expected_demand: &'a mut i64,
+    pub smoothing: i64,
+    pub target_stock: i64,
+    pub stock_adjustment: i64,
+    pub supply_line_weight: i64,
+}
+
+impl OrderPolicy for AnchorAndAdjust<'_> {
+    fn order(&mut self, view: &BotView) -> i64 {
+        *self.expected_demand += (view.requested - *self.expected_demand) * self.smoothing / 100;
+
+        let stock_gap = self.target_stock - view.net_stock();
+        let supply_line_gap = -view.supply_line() * self.supply_line_weight / 100;
+        let adjustment = (stock_gap + supply_line_gap) * self.stock_adjustment / 100;
+
+        (*self.expected_demand + adjustment).max(0)
+    }
+}
+
+/// Draws from the game's random numbers, so a replayed game orders the same.
+pub struct RandomOrder<'a, R: Rng> {
+    pub min: i64,
+    pub max: i64,
+    pub rng: &'a mut R,
+}
+
+impl<R: Rng> OrderPolicy for RandomOrder<'_, R> {
+    fn order(&mut self, _view: &BotView) -> i64 {
+        if self.max <= self.min {
+            return self.min.max(0);
+        }
+        self.rng.gen_range(self.min..=self.max).max(0)
+    }
+}
+
+/// Order value the bot places this round, updating its memory.
+pub fn bot_order(bot: &mut Bot, view: &BotView, rng: &mut impl Rng) -> i64 {
+    match bot.policy {
+        BotPolicy::PassThrough => PassThrough.order(view),
+        BotPolicy::BaseStock { target } => BaseStock { target }.order(view),
+        BotPolicy::AnchorAndAdjust {
+            smoothing,
+            target_stock,
+            stock_adjustment,
+            supply_line_weight,
+        } => AnchorAndAdjust {
+            expected_demand: &mut bot.expected_demand,
+            smoothing,
+            target_stock,
+            stock_adjustment,
+            supply_line_weight,
+        }
+        .order(view),
+        BotPolicy::Random { min, max } => RandomOrder { min, max, rng }.order(view),
+    }
+}
@@ -0,0 +1,2344 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    f64::consts::{E, PI},
+};
+
+use rand::{seq::SliceRandom, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rand_distr::{Distribution, Normal, Poisson};
+use uuid::Uuid;
+
+use crate::{
+    entities::{
+        ActionTarget, BankruptcyOutcome, BankruptcyPolicy, Bot, BotPolicy, ChainPosition,
+        Comparison, DefaultOrderPolicy, DelayedShipment, DemandPromotion, Disruption, EventAction,
+        EventCondition, Flow, GameEvent, GameEvents, GeneratedOrderStyle, ListEnd, MetBy, Order,
+        PerformanceMetric, PerformanceStats, PlayerGroup, ProductRound, Resource, RoundCosts,
+        Settings, Stock, Supply, Team, UserState, WarehouseOverflow,
+    },
+    error::AppError,
+    websockets::EventMessages,
+    RoundState,
+};
+
+use super::{
+    bots::{bot_order, BotView},
+    game::{GameUpdate, PlayerOrder, StartGame, TeamSetup, UserEndRound},
+};
+
+/// Pure round simulation. Takes a `RoundState`, applies player orders and round
+/// transitions to it and collects the messages that should be broadcast.
+/// Persistence and broadcasting is left to the caller.
+#[derive(Debug, Clone)]
+pub struct GameEngine {
+    round_state: RoundState,
+    events: Vec<EventMessages>,
+    finished_round: Option<RoundState>,
+    game_finished: bool,
+}
+
+impl GameEngine {
+    pub fn new(round_state: RoundState) -> Self {
+        Self {
+            round_state,
+            events: Vec::new(),
+            finished_round: None,
+            game_finished: false,
+        }
+    }
+
+    /// Builds the round 0 state for a new game. Without a flow the players are
+    /// placed in a chain in the given order.
+    pub fn start_game(
+        settings: Settings,
+        players: &[Uuid],
+        player_classes: BTreeMap<Uuid, u32>,
+        flow: Option<Flow>,
+    ) -> Result<Self, AppError> {
+        if players.is_empty() {
+            return Err(AppError::BadRequest(
+                "no players to start a game".to_string(),
+            ));
+        }
+
+        let flow = match flow {
+            Some(f) => {
+                f.validate(players)?;
+                f
+            }
+            None => Flow::chain(players),
+        };
+        let demand = start_value(&settings.demand_style)?;
+        let supply = start_value(&settings.supply_style)?;
+        validate_warehouse(&settings)?;
+
+        let mut products = BTreeMap::new();
+        for product in &settings.products {
+            if product.name.is_empty() {
+                return Err(AppError::BadRequest("product without a name".to_string()));
+            }
+            let product_round = ProductRound {
+                demand: start_value(&product.demand_style)?,
+                supply: Supply::new(start_value(&product.supply_style)?),
+            };
+            if products
+                .insert(product.name.clone(), product_round)
+                .is_some()
+            {
+                return Err(AppError::BadRequest(
+                    "product names have to be unique".to_string(),
+                ));
+            }
+        }
+
+        let mut users_states = BTreeMap::new();
+
+        for player in players {
+            let player_class = match player_classes.get(player) {
+                Some(c) => *c,
+                None => return Err(AppError::BadRequest("Player not found".to_string())),
+            };
+
+            let start_money = class_value(&settings.start_money, player_class, "start money")?;
+            let start_magazine =
+                class_value(&settings.start_magazine, player_class, "start magazine")?;
+
+            let incoming_orders_values = start_queue(
+                settings.incoming_start_queue.get(&player_class),
+                settings.shipping_delay.get(&player_class),
+                demand,
+                "shipping",
+            )?;
+            let requested_orders_values = start_queue(
+                settings.requested_start_queue.get(&player_class),
+                settings.order_delay.get(&player_class),
+                demand,
+                "order",
+            )?;
+
+            // the production line starts full, like the shipping queues
+            let producer =
+                settings.production_capacity.contains_key(&player_class) && flow.is_source(player);
+            let lead_time = producer.then(|| {
+                let lead_time = settings.production_lead_time.get(&player_class);
+                lead_time.copied().unwrap_or(0)
+            });
+
+            let start = StartStock {
+                player: *player,
+                flow: &flow,
+                lead_time,
+            };
+            let stock = start.stock(
+                &incoming_orders_values,
+                &requested_orders_values,
+                demand,
+                settings.resource_basic_price,
+                start_magazine,
+            );
+
+            // additional products use the same delays, filled with their own demand
+            let mut product_stocks = BTreeMap::new();
+            for product in &settings.products {
+                let product_demand = products[&product.name].demand;
+                let stock = start.stock(
+                    &vec![product_demand; incoming_orders_values.len()],
+                    &vec![product_demand; requested_orders_values.len()],
+                    product_demand,
+                    product.resource_basic_price,
+                    optional_class_value(&product.start_magazine, player_class),
+                );
+                product_stocks.insert(product.name.clone(), stock);
+            }
+
+            users_states.insert(
+                *player,
+                UserState {
+                    user_id: *player,
+                    money: start_money,
+                    spent_money: 0,
+                    performance: 0,
+                    stock,
+                    round_costs: RoundCosts::default(),
+                    eliminated: false,
+                    performance_stats: PerformanceStats::default(),
+                    bot: None,
+                    products: product_stocks,
+                },
+            );
+        }
+
+        let round_state = RoundState {
+            round: 0,
+            players: players.len() as i64,
+            players_finished: 0,
+            users_states,
+            round_orders: BTreeMap::new(),
+            send_orders: BTreeMap::new(),
+            player_classes,
+            settings,
+            flow,
+            demand,
+            supply: Supply::new(supply),
+            products,
+            team: Team::default(),
+            seed: 0,
+            fired_events: BTreeMap::new(),
+            disruptions: Vec::new(),
+            delayed_shipments: Vec::new(),
+        };
+
+        let mut engine = Self::new(round_state);
+        let update = engine.game_update(BTreeMap::new(), BTreeMap::new());
+        engine.emit(EventMessages::GameStart(update));
+        engine.emit_round_deadline();
+
+        Ok(engine)
+    }
+
+    /// Starts a game for every team of the lobby. Without teams all players form a
+    /// single chain, otherwise each player has to be in exactly one team. Bots not
+    /// replacing a lobby player join as new players of their class.
+    pub fn start_teams(
+        settings: &Settings,
+        mut players: Vec<Uuid>,
+        start: StartGame,
+        rng: &mut impl Rng,
+    ) -> Result<Vec<Self>, AppError> {
+        let mut player_classes = start.player_classes;
+        for (bot, setup) in &start.bots {
+            if players.contains(bot) {
+                continue;
+            }
+
+            match setup.class {
+                Some(c) => {
+                    players.push(*bot);
+                    player_classes.insert(*bot, c);
+                }
+                None => {
+                    return Err(AppError::BadRequest(
+                        "class needed for a bot joining the game".to_string(),
+                    ))
+                }
+            }
+        }
+
+        let mut teams = start.teams;
+        if teams.is_empty() {
+            teams.push(TeamSetup {
+                name: String::new(),
+                players: players.clone(),
+                flow: start.flow,
+                order: start.order,
+            });
+        }
+
+        let mut assigned: Vec<Uuid> = teams
+            .iter()
+            .flat_map(|t| t.players.iter().copied())
+            .collect();
+        assigned.sort();
+        players.sort();
+        if assigned != players {
+            return Err(AppError::BadRequest(
+                "every player has to be in exactly one team".to_string(),
+            ));
+        }
+
+        let names: BTreeSet<&String> = teams.iter().map(|t| &t.name).collect();
+        if names.len() != teams.len() {
+            return Err(AppError::BadRequest(
+                "team names have to be unique".to_string(),
+            ));
+        }
+
+        let seed = start.seed.unwrap_or_else(|| rng.gen());
+        let single_team = teams.len() == 1;
+        let mut engines = Vec::with_capacity(teams.len());
+        for team in teams {
+            let team_classes: BTreeMap<Uuid, u32> = player_classes
+                .iter()
+                .filter(|(p, _)| team.players.contains(p))
+                .map(|(p, c)| (*p, *c))
+                .collect();
+            let players = order_players(team.players, &team.order, &team_classes, rng)?;
+
+            let mut engine = Self::start_game(settings.clone(), &players, team_classes, team.flow)?;
+            engine.round_state.seed = seed;
+            if !single_team {
+                engine.round_state.team = Team {
+                    id: Uuid::from_u128(rng.gen()),
+                    name: team.name,
+                };
+            }
+
+            for (bot, setup) in &start.bots {
+                if let Some(user_state) = engine.round_state.users_states.get_mut(bot) {
+                    user_state.bot = Some(Bot {
+                        policy: setup.policy.clone(),
+                        expected_demand: engine.round_state.demand,
+                    });
+                }
+            }
+
+            // the start update has to show the team and its bots
+            let start_update = engine.game_update(BTreeMap::new(), BTreeMap::new());
+            for event in engine.events.iter_mut() {
+                if let EventMessages::GameStart(update) = event {
+                    *update = start_update.clone();
+                }
+            }
+            // saved as the first round before the bots play, a rewind lets them play
+            // it again
+            engine.finished_round = Some(engine.round_state.clone());
+            engine.play_bots()?;
+
+            engines.push(engine);
+        }
+
+        Ok(engines)
+    }
+
+    /// Hands the position of `player` over to a bot, or back to the player without
+    /// a policy. The bot places its order right away if the player didn't yet.
+    pub fn set_bot(&mut self, player: Uuid, policy: Option<BotPolicy>) -> Result<(), AppError> {
+        let expected_demand = self.round_state.demand;
+        let user_state = match self.round_state.users_states.get_mut(&player) {
+            Some(us) => us,
+            None => return Err(AppError::BadRequest("player not in the game".to_string())),
+        };
+
+        user_state.bot = policy.map(|policy| Bot {
+            policy,
+            expected_demand,
+        });
+
+        self.play_bots()
+    }
+
+    pub fn round_finished(&self) -> bool {
+        self.round_state.players_finished == self.round_state.players
+    }
+
+    pub fn game_finished(&self) -> bool {
+        self.game_finished
+    }
+
+    /// State of the round closed by `finish_round`, with its orders and before game
+    /// events were applied, or of the first round before bots played in it. This is
+    /// what gets saved as the round snapshot.
+    pub fn finished_round(&self) -> Option<&RoundState> {
+        self.finished_round.as_ref()
+    }
+
+    /// Consumes the engine, returning the new round state and the messages emitted
+    /// while processing.
+    pub fn finish(self) -> (RoundState, Vec<EventMessages>) {
+        (self.round_state, self.events)
+    }
+
+    fn emit(&mut self, msg: EventMessages) {
+        self.events.push(msg);
+    }
+
+    /// Applies the order placed by `player` at the end of the round: pays for the
+    /// order and storage, takes in the incoming delivery and ships what was requested.
+    pub fn end_player_round(&mut self, player: Uuid, placed_order: Order) -> Result<(), AppError> {
+        self.end_player_round_with_products(player, placed_order, BTreeMap::new())
+    }
+
+    /// Same as `end_player_round`, with the orders for the additional products.
+    /// Products without an order get an empty one.
+    pub fn end_player_round_with_products(
+        &mut self,
+        player: Uuid,
+        placed_order: Order,
+        mut product_orders: BTreeMap<String, Order>,
+    ) -> Result<(), AppError> {
+        let round_state = &mut self.round_state;
+
+        let player_class = match round_state.player_classes.get(&player) {
+            Some(c) => *c,
+            None => {
+                return Err(AppError::BadRequest(
+                    "class for player not found".to_string(),
+                ))
+            }
+        };
+
+        let settings = &round_state.settings;
+        let resource_price = class_value(&settings.resource_price, player_class, "resource price")?;
+        let fix_order_cost = class_value(&settings.fix_order_cost, player_class, "fix_order_cost")?;
+        let magazine_cost = class_value(&settings.magazine_cost, player_class, "magazine_cost")?;
+        let back_order_cost = optional_class_value(&settings.back_order_cost, player_class);
+        let transport_cost = optional_class_value(&settings.transport_cost, player_class);
+        let additional_cost = optional_class_value(&settings.additional_cost, player_class);
+        let production_cost = optional_class_value(&settings.production_cost, player_class);
+        let warehouse_capacity = settings.warehouse_capacity.get(&player_class).copied();
+        let spoilage_rate = optional_class_value(&settings.spoilage_rate, player_class);
+
+        if round_state.round_orders.contains_key(&player) {
+            return Err(AppError::BadOrder(
+                "order already placed this round".to_string(),
+            ));
+        }
+
+        if let Some(name) = product_orders
+            .keys()
+            .find(|name| !settings.products.iter().any(|p| &p.name == *name))
+        {
+            return Err(AppError::BadOrder(format!("unknown product {}", name)));
+        }
+
+        let mut orders = vec![ProductOrder {
+            product: None,
+            order: placed_order,
+            resource_price,
+            magazine_cost,
+        }];
+        for product in &settings.products {
+            orders.push(ProductOrder {
+                product: Some(product.name.clone()),
+                order: product_orders.remove(&product.name).unwrap_or_default(),
+                resource_price: class_value(
+                    &product.resource_price,
+                    player_class,
+                    "product resource price",
+                )?,
+                magazine_cost: class_value(
+                    &product.magazine_cost,
+                    player_class,
+                    "product magazine cost",
+                )?,
+            });
+        }
+
+        let producer = is_producer(round_state, player, player_class);
+        if let Some(capacity) = production_capacity(round_state, player_class) {
+            let values: Vec<i64> = orders.iter().map(|o| o.order.value).collect();
+            if producer && values.iter().sum::<i64>() > capacity {
+                // a factory can't start more than it manufactures in a round, the
+                // capacity is shared by all products in proportion to the orders
+                let parts = allocate(capacity.max(0), &values);
+                for (ProductOrder { order, .. }, value) in orders.iter_mut().zip(parts) {
+                    // nothing to scale for an empty order
+                    if order.value != 0 && value < order.value {
+                        order.cost = order.cost * value / order.value;
+                        order.value = value;
+                    }
+                }
+            }
+        }
+        let order_cost: i64 = orders.iter().map(|o| o.order.cost).sum();
+        let blocked = blocked_customers(round_state, player, player_class);
+
+        let user_state = match round_state.users_states.get_mut(&player) {
+            Some(us) => us,
+            None => {
+                return Err(AppError::InternalServerError(
+                    "expected a user state".to_string(),
+                ))
+            }
+        };
+
+        let money_checked = !settings.unlimited_money && !user_state.eliminated;
+        if money_checked
+            && settings.bankruptcy_policy == BankruptcyPolicy::RejectOrder
+            && order_cost > user_state.money.max(0)
+        {
+            self.emit(EventMessages::ErrorUser(
+                player,
+                AppError::BadOrder("not enough money for placed order".to_string()),
+            ));
+            self.emit(EventMessages::Bankruptcy(
+                player,
+                BankruptcyOutcome::OrderRejected,
+            ));
+            return Ok(());
+        }
+
+        let senders = round_state.flow.get_senders_weights(&player);
+        let customers = round_state.flow.get_customers(&player);
+        let links = Links {
+            player,
+            senders: &senders,
+            customers: &customers,
+            blocked: &blocked,
+        };
+
+        let mut round_costs = RoundCosts {
+            order_cost,
+            additional_cost,
+            ..RoundCosts::default()
+        };
+
+        let mut requested_value = 0;
+        let mut send_order_val = 0;
+        let mut main_orders = None;
+        let mut deliveries = Vec::new();
+        let mut split_orders = Vec::new();
+        for product_order in orders {
+            let product = product_order.product;
+            let stock = match user_state.product_stock_mut(product.as_deref()) {
+                Some(s) => s,
+                None => {
+                    return Err(AppError::InternalServerError(
+                        "expected a product stock".to_string(),
+                    ))
+                }
+            };
+            round_costs.magazine_cost += stock.magazine_state * product_order.magazine_cost;
+
+            let moves = links.move_stock(
+                stock,
+                product_order.order,
+                product_order.resource_price,
+                fix_order_cost,
+            )?;
+            if producer {
+                round_costs.production_cost += stock.received_order.value * production_cost;
+            }
+
+            requested_value += moves.requested;
+            send_order_val += moves.send_order.value;
+            deliveries.extend(moves.deliveries.into_iter().map(|o| (product.clone(), o)));
+            split_orders.extend(moves.split_orders.into_iter().map(|o| (product.clone(), o)));
+            if product.is_none() {
+                main_orders = Some((stock.placed_order.clone(), moves.send_order));
+            }
+        }
+
+        round_costs.surcharge_cost = store_goods(
+            user_state,
+            warehouse_capacity,
+            &settings.warehouse_overflow,
+            spoilage_rate,
+        );
+        round_costs.transport_cost = send_order_val * transport_cost;
+        round_costs.back_order_cost = back_order_total(user_state) * back_order_cost;
+
+        let money_before = user_state.money;
+        let total_cost = round_costs.total();
+        user_state.money -= total_cost;
+        user_state.spent_money += total_cost;
+
+        let mut bankruptcy = None;
+        if money_checked && user_state.money < 0 {
+            match settings.bankruptcy_policy {
+                BankruptcyPolicy::RejectOrder => {}
+                BankruptcyPolicy::NegativeBalance { interest_rate } => {
+                    round_costs.interest_cost = -user_state.money * interest_rate / 100;
+                    user_state.money -= round_costs.interest_cost;
+                    user_state.spent_money += round_costs.interest_cost;
+
+                    if money_before >= 0 {
+                        bankruptcy = Some(BankruptcyOutcome::NegativeBalance);
+                    }
+                }
+                BankruptcyPolicy::Eliminate => {
+                    user_state.eliminated = true;
+                    bankruptcy = Some(BankruptcyOutcome::Eliminated);
+                }
+            }
+        }
+        user_state.round_costs = round_costs;
+        update_performance(
+            user_state,
+            &settings.performance_metric,
+            requested_value,
+            send_order_val,
+        );
+
+        if let Some((placed_order, send_order)) = main_orders {
+            round_state.send_orders.insert(player, send_order);
+            round_state.round_orders.insert(player, placed_order);
+        }
+        round_state.players_finished += 1;
+
+        for (product, order) in deliveries {
+            let order = delay_delivery(round_state, product.as_deref(), player_class, order);
+            push_order(
+                &mut round_state.users_states,
+                order.recipient,
+                product.as_deref(),
+                order,
+                |s| &mut s.incoming_orders,
+            )?;
+        }
+        for (product, order) in split_orders {
+            push_order(
+                &mut round_state.users_states,
+                order.sender,
+                product.as_deref(),
+                order,
+                |s| &mut s.requested_orders,
+            )?;
+        }
+
+        self.emit(EventMessages::Ack(player));
+        if let Some(outcome) = bankruptcy {
+            self.emit(EventMessages::Bankruptcy(player, outcome));
+        }
+
+        Ok(())
+    }
+
+    /// Places orders for players that didn't send one before the round time limit
+    /// ran out, following the default order policy. When the player can't pay for
+    /// it nothing is ordered.
+    pub fn submit_missing_orders(&mut self) -> Result<(), AppError> {
+        let policy = self.round_state.settings.default_order.clone();
+        let order_value = |player: Uuid, stock: &Stock| match &policy {
+            DefaultOrderPolicy::LastOrder => stock.placed_order.value,
+            DefaultOrderPolicy::Zero => 0,
+            DefaultOrderPolicy::Fixed { value } => *value,
+            DefaultOrderPolicy::PassThrough => self.requested_value(player, stock),
+        };
+        let missing: Vec<(Uuid, Order, BTreeMap<String, Order>)> = self
+            .round_state
+            .users_states
+            .values()
+            .filter(|us| !self.round_state.round_orders.contains_key(&us.user_id))
+            .map(|us| {
+                let value = order_value(us.user_id, &us.stock);
+                let product_orders = self.product_orders(us, |s| order_value(us.user_id, s));
+                (us.user_id, self.basic_order(value), product_orders)
+            })
+            .collect();
+
+        let players: Vec<Uuid> = missing.iter().map(|(p, _, _)| *p).collect();
+        for (player, order, product_orders) in missing {
+            self.end_player_round_with_products(player, order, product_orders)?;
+            if !self.round_state.round_orders.contains_key(&player) {
+                self.end_player_round(player, Order::default())?;
+            }
+        }
+
+        self.emit(EventMessages::RoundTimeout(players));
+        Ok(())
+    }
+
+    /// What the player was asked for by its customers this round.
+    fn requested_value(&self, player: Uuid, stock: &Stock) -> i64 {
+        self.round_state
+            .flow
+            .get_customers(&player)
+            .iter()
+            .filter_map(|c| stock.requested_orders.iter().find(|o| o.recipient == *c))
+            .map(|o| o.value)
+            .sum()
+    }
+
+    fn basic_order(&self, value: i64) -> Order {
+        Order {
+            value,
+            cost: value * self.round_state.settings.resource_basic_price,
+            ..Order::default()
+        }
+    }
+
+    /// Orders for the additional products of a player, worth `value` of each stock.
+    fn product_orders(
+        &self,
+        user_state: &UserState,
+        value: impl Fn(&Stock) -> i64,
+    ) -> BTreeMap<String, Order> {
+        self.round_state
+            .settings
+            .products
+            .iter()
+            .filter_map(|product| {
+                let value = value(user_state.products.get(&product.name)?);
+                let order = Order {
+                    value,
+                    cost: value * product.resource_basic_price,
+                    ..Order::default()
+                };
+                Some((product.name.clone(), order))
+            })
+            .collect()
+    }
+
+    fn emit_round_deadline(&mut self) {
+        if let Some(seconds) = self.round_state.settings.round_time_limit {
+            self.emit(EventMessages::RoundDeadline(
+                self.round_state.round,
+                seconds,
+            ));
+        }
+    }
+
+    /// Places the orders of bots that didn't play this round yet, through the same
+    /// round end message as players. Bots follow their policy for the main product
+    /// and pass on the orders for the other ones.
+    fn play_bots(&mut self) -> Result<(), AppError> {
+        let mut messages = Vec::new();
+        for user_state in self.round_state.users_states.values() {
+            let mut bot = match &user_state.bot {
+                Some(b) if !user_state.eliminated => b.clone(),
+                _ => continue,
+            };
+            let player = user_state.user_id;
+            if self.round_state.round_orders.contains_key(&player) {
+                continue;
+            }
+
+            let view = BotView {
+                stock: &user_state.stock,
+                requested: self.requested_value(player, &user_state.stock),
+            };
+            let mut rng = round_rng(
+                self.round_state.seed,
+                self.round_state.round,
+                BOT_STREAM.wrapping_add(player.as_u128() as u64),
+            );
+            let value = bot_order(&mut bot, &view, &mut rng);
+            let msg = UserEndRound {
+                placed_order: self.basic_order(value),
+                product_orders: self
+                    .product_orders(user_state, |s| self.requested_value(player, s)),
+            };
+            messages.push((player, bot, msg));
+        }
+
+        for (player, bot, msg) in messages {
+            if let Some(user_state) = self.round_state.users_states.get_mut(&player) {
+                user_state.bot = Some(bot);
+            }
+            self.end_player_round_with_products(player, msg.placed_order, msg.product_orders)?;
+            if !self.round_state.round_orders.contains_key(&player) {
+                self.end_player_round(player, Order::default())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Places orders for eliminated players, a stand-in passes on what was
+    /// requested from it.
+    fn play_stand_ins(&mut self) -> Result<(), AppError> {
+        let stand_ins: Vec<(Uuid, Order, BTreeMap<String, Order>)> = self
+            .round_state
+            .users_states
+            .values()
+            .filter(|us| us.eliminated)
+            .map(|us| {
+                let order = self.basic_order(self.requested_value(us.user_id, &us.stock));
+                let product_orders =
+                    self.product_orders(us, |s| self.requested_value(us.user_id, s));
+                (us.user_id, order, product_orders)
+            })
+            .collect();
+
+        for (player, order, product_orders) in stand_ins {
+            self.end_player_round_with_products(player, order, product_orders)?;
+        }
+
+        Ok(())
+    }
+
+    /// Closes the round once every player placed an order: generates demand and
+    /// supply, routes orders along the flow and moves to the next round, running
+    /// game events unless the game is over. `last_users_states` are the player
+    /// states saved for the current round, used by change based events.
+    pub fn finish_round(
+        &mut self,
+        game_events: &GameEvents,
+        last_users_states: Option<&BTreeMap<Uuid, UserState>>,
+    ) -> Result<(), AppError> {
+        self.emit(EventMessages::RoundEnd);
+
+        let round_state = &mut self.round_state;
+
+        let players: Vec<Uuid> = round_state.users_states.keys().copied().collect();
+        let sinks: Vec<Uuid> = players
+            .iter()
+            .filter(|p| round_state.flow.is_sink(p))
+            .copied()
+            .collect();
+        let sources: Vec<Uuid> = players
+            .iter()
+            .filter(|p| round_state.flow.is_source(p))
+            .copied()
+            .collect();
+
+        tracing::debug!("finishing round, generating demand");
+        let basic_price = round_state.settings.resource_basic_price;
+        let next_round = round_state.round + 1;
+        let next_demand = generate_demand(
+            round_state.demand,
+            &round_state.settings.demand_style,
+            next_round,
+            &mut round_rng(round_state.seed, next_round, DEMAND_STREAM),
+        );
+        // generators keep going from the unscaled demand
+        let demand_percent = demand_percent(round_state);
+        let scaled_demand = next_demand * demand_percent / 100;
+        push_demand(round_state, None, &sinks, scaled_demand, basic_price)?;
+
+        round_state.round_orders.insert(
+            Uuid::nil(),
+            Order {
+                recipient: Uuid::nil(),
+                sender: single_or_nil(sinks.iter().copied()),
+                value: scaled_demand,
+                cost: basic_price * scaled_demand,
+            },
+        );
+
+        let capacity = generate_demand(
+            round_state.supply.capacity,
+            &round_state.settings.supply_style,
+            next_round,
+            &mut round_rng(round_state.seed, next_round, SUPPLY_STREAM),
+        );
+        let supply = push_supply(round_state, None, &sources, capacity, basic_price)?;
+
+        round_state.send_orders.insert(
+            Uuid::nil(),
+            Order {
+                recipient: single_or_nil(sources.iter().copied()),
+                sender: Uuid::nil(),
+                value: supply.delivered,
+                cost: basic_price * supply.delivered,
+            },
+        );
+
+        // every product draws from its own pair of random streams
+        let products = round_state.settings.products.clone();
+        for (index, product) in products.iter().enumerate() {
+            let last = match round_state.products.get(&product.name) {
+                Some(p) => p.clone(),
+                None => {
+                    return Err(AppError::InternalServerError(format!(
+                        "expected the state of product {}",
+                        product.name
+                    )))
+                }
+            };
+            let streams = 2 * (index as u64 + 1);
+            let name = Some(product.name.as_str());
+
+            let demand = generate_demand(
+                last.demand,
+                &product.demand_style,
+                next_round,
+                &mut round_rng(round_state.seed, next_round, DEMAND_STREAM + streams),
+            );
+            push_demand(
+                round_state,
+                name,
+                &sinks,
+                demand * demand_percent / 100,
+                product.resource_basic_price,
+            )?;
+
+            let capacity = generate_demand(
+                last.supply.capacity,
+                &product.supply_style,
+                next_round,
+                &mut round_rng(round_state.seed, next_round, SUPPLY_STREAM + streams),
+            );
+            let supply = push_supply(
+                round_state,
+                name,
+                &sources,
+                capacity,
+                product.resource_basic_price,
+            )?;
+            round_state
+                .products
+                .insert(product.name.clone(), ProductRound { demand, supply });
+        }
+
+        round_state.round += 1;
+        let round = round_state.round;
+        round_state.disruptions.retain(|d| d.is_active(round));
+        round_state.demand = next_demand;
+        round_state.supply = supply;
+        self.finished_round = Some(round_state.clone());
+
+        // an event may have lowered the limit below the current round
+        if round_state.round >= round_state.settings.max_rounds
+            || demand_ended(&round_state.settings, round_state.round)
+        {
+            self.game_finished = true;
+            return Ok(());
+        }
+
+        self.process_game_events(game_events, last_users_states)?;
+        if let Some(finished_round) = &mut self.finished_round {
+            // saved with the round so what its events changed is known after a
+            // restore, a rewind starts from the settings of the round before
+            finished_round.fired_events = self.round_state.fired_events.clone();
+            finished_round.disruptions = self.round_state.disruptions.clone();
+            finished_round.settings = self.round_state.settings.clone();
+        }
+        self.new_round()
+    }
+
+    /// Starts again the round of a saved snapshot. Its game events run again like
+    /// when the round was first reached, there are none before the first round. The
+    /// settings have to be the ones from before the events, saved with the round
+    /// before.
+    pub fn rewind(
+        &mut self,
+        game_events: &GameEvents,
+        last_users_states: Option<&BTreeMap<Uuid, UserState>>,
+    ) -> Result<(), AppError> {
+        let round = self.round_state.round;
+        for fired in self.round_state.fired_events.values_mut() {
+            fired.retain(|r| *r < round);
+        }
+        self.round_state.disruptions.retain(|d| d.start < round);
+        if round > 0 {
+            self.process_game_events(game_events, last_users_states)?;
+        }
+
+        self.new_round()
+    }
+
+    fn new_round(&mut self) -> Result<(), AppError> {
+        let round_orders = std::mem::take(&mut self.round_state.round_orders);
+        let send_orders = std::mem::take(&mut self.round_state.send_orders);
+        self.round_state.players_finished = 0;
+
+        let update = self.game_update(round_orders, send_orders);
+        self.emit(EventMessages::RoundStart(update));
+        self.emit_round_deadline();
+
+        self.play_stand_ins()?;
+        self.play_bots()?;
+        if self
+            .round_state
+            .users_states
+            .values()
+            .all(|us| us.eliminated)
+        {
+            // every player was eliminated, nobody is left to play
+            self.game_finished = true;
+        }
+
+        Ok(())
+    }
+
+    fn game_update(
+        &self,
+        round_orders: BTreeMap<Uuid, Order>,
+        send_orders: BTreeMap<Uuid, Order>,
+    ) -> GameUpdate {
+        GameUpdate {
+            player_states: self.round_state.users_states.clone(),
+            round: self.round_state.round,
+            flow: self.round_state.flow.clone(),
+            settings: self.round_state.settings.clone(),
+            round_orders,
+            send_orders,
+            player_classes: self.round_state.player_classes.clone(),
+            team: self.round_state.team.clone(),
+            supply: self.round_state.supply,
+            products: self.round_state.products.clone(),
+            seed: Some(self.round_state.seed),
+        }
+    }
+
+    fn process_game_events(
+        &mut self,
+        game_events: &GameEvents,
+        last_users_states: Option<&BTreeMap<Uuid, UserState>>,
+    ) -> Result<(), AppError> {
+        tracing::debug!("processing events, count: {}", game_events.events.len());
+        let round = self.round_state.round;
+        for (index, event) in game_events.events.iter().enumerate() {
+            let fired = self.round_state.fired_events.get(&index);
+            if !event.is_active(round, fired.map(Vec::as_slice).unwrap_or_default()) {
+                continue;
+            }
+
+            tracing::debug!("processing event: {}", event.name);
+            let (cond_met, targets) = match self.evaluate_cond(event, last_users_states) {
+                Ok(c) => c,
+                Err(e) => {
+                    // a broken event can't keep the round from closing
+                    self.emit(EventMessages::Error(e));
+                    continue;
+                }
+            };
+
+            if !cond_met {
+                continue;
+            }
+            self.round_state
+                .fired_events
+                .entry(index)
+                .or_default()
+                .push(round);
+
+            for action in &event.actions {
+                if let Err(e) = self.execute_action(action, &targets) {
+                    tracing::error!("action of event {} failed: {}", event.name, e);
+                    self.emit(EventMessages::Error(e));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn execute_action(&mut self, action: &EventAction, targets: &[Uuid]) -> Result<(), AppError> {
+        match action.clone() {
+            EventAction::ShowMessage { message, target } => {
+                self.execute_pop_up_action(target, targets, message)
+            }
+            EventAction::ChangeSettings { new_settings } => {
+                self.round_state.settings = new_settings.clone();
+                self.emit(EventMessages::GameEventSettingsChange(new_settings));
+            }
+            EventAction::PatchSettings { patch } => {
+                let settings = self.round_state.settings.patched(&patch)?;
+                self.round_state.settings = settings.clone();
+                self.emit(EventMessages::GameEventSettingsChange(settings));
+            }
+            EventAction::AddResource {
+                resource,
+                target,
+                value,
+            } => self.execute_resource_action(target, targets, resource, value)?,
+            EventAction::ChangeProductionCapacity { class, capacity } => {
+                self.round_state
+                    .settings
+                    .production_capacity
+                    .insert(class, capacity);
+                self.emit(EventMessages::GameEventProductionCapacity(class, capacity));
+            }
+            EventAction::BlockShipments { link, rounds } => self.start_disruption(
+                action,
+                EventMessages::GameEventShipmentsBlocked(link, rounds),
+            ),
+            EventAction::DelayShipments {
+                link,
+                delay,
+                rounds,
+            } => self.start_disruption(
+                action,
+                EventMessages::GameEventShipmentsDelayed(link, delay, rounds),
+            ),
+            EventAction::ScaleDemand { percent, rounds } => self.start_disruption(
+                action,
+                EventMessages::GameEventDemandScaled(percent, rounds),
+            ),
+            EventAction::CutProductionCapacity {
+                class,
+                percent,
+                rounds,
+            } => self.start_disruption(
+                action,
+                EventMessages::GameEventProductionCut(class, percent, rounds),
+            ),
+            EventAction::ChangePrice {
+                class,
+                product,
+                price,
+            } => self.change_price(class, product, price)?,
+            EventAction::DemandShock { value } => self.demand_shock(value),
+        }
+
+        Ok(())
+    }
+
+    fn start_disruption(&mut self, action: &EventAction, msg: EventMessages) {
+        self.round_state.disruptions.push(Disruption {
+            start: self.round_state.round,
+            action: action.clone(),
+        });
+        self.emit(msg);
+    }
+
+    fn change_price(
+        &mut self,
+        class: u32,
+        product: Option<String>,
+        price: i64,
+    ) -> Result<(), AppError> {
+        let settings = &mut self.round_state.settings;
+        let prices = match &product {
+            None => &mut settings.resource_price,
+            Some(name) => match settings.products.iter_mut().find(|p| &p.name == name) {
+                Some(p) => &mut p.resource_price,
+                None => return Err(AppError::BadRequest(format!("unknown product {}", name))),
+            },
+        };
+        prices.insert(class, price);
+        self.emit(EventMessages::GameEventPriceChange(class, product, price));
+
+        Ok(())
+    }
+
+    /// Changes the customer demand generated for the current round, it can't go
+    /// below zero.
+    fn demand_shock(&mut self, value: i64) {
+        let round_state = &mut self.round_state;
+        let basic_price = round_state.settings.resource_basic_price;
+        let flow = &round_state.flow;
+        for (_, user_state) in round_state
+            .users_states
+            .iter_mut()
+            .filter(|(p, _)| flow.is_sink(p))
+        {
+            let requested = user_state
+                .stock
+                .requested_orders
+                .iter_mut()
+                .rev()
+                .find(|o| o.recipient.is_nil());
+            if let Some(order) = requested {
+                order.value = (order.value + value).max(0);
+                order.cost = basic_price * order.value;
+            }
+        }
+        if let Some(order) = round_state.round_orders.get_mut(&Uuid::nil()) {
+            order.value = (order.value + value).max(0);
+            order.cost = basic_price * order.value;
+        }
+
+        self.emit(EventMessages::GameEventDemandShock(value));
+    }
+
+    fn execute_resource_action(
+        &mut self,
+        target: ActionTarget,
+        players_targets: &[Uuid],
+        resource: Resource,
+        value: i64,
+    ) -> Result<(), AppError> {
+        match target {
+            ActionTarget::AllPlayers => {
+                for player_state in self.round_state.users_states.values_mut() {
+                    add_resource(player_state, &resource, value);
+                }
+                self.emit(EventMessages::GameEventResourceAddedAll(resource, value));
+            }
+            target => {
+                for u_id in &self.action_players(&target, players_targets) {
+                    let player_state = match self.round_state.users_states.get_mut(u_id) {
+                        Some(p) => p,
+                        None => {
+                            return Err(AppError::InternalServerError(
+                                "expected user state".to_string(),
+                            ))
+                        }
+                    };
+                    add_resource(player_state, &resource, value);
+                    self.emit(EventMessages::GameEventResourceAddedUser(
+                        *u_id,
+                        resource.clone(),
+                        value,
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn execute_pop_up_action(
+        &mut self,
+        target: ActionTarget,
+        players_targets: &[Uuid],
+        message: String,
+    ) {
+        match target {
+            ActionTarget::AllPlayers => self.emit(EventMessages::GameEventPopUpAll(message)),
+            target => {
+                for player_id in self.action_players(&target, players_targets) {
+                    self.emit(EventMessages::GameEventPopUpUser(
+                        player_id,
+                        message.clone(),
+                    ));
+                }
+            }
+        }
+    }
+
+    fn action_players(&self, target: &ActionTarget, players_targets: &[Uuid]) -> Vec<Uuid> {
+        match target {
+            ActionTarget::EventTarget => players_targets.to_vec(),
+            ActionTarget::AllPlayers => self.round_state.users_states.keys().copied().collect(),
+            ActionTarget::Players(group) => {
+                let reference = players_targets.iter().copied().collect();
+                self.group_players(group, &reference).into_iter().collect()
+            }
+        }
+    }
+
+    /// Players of a group, neighbors are looked up for the `reference` players.
+    fn group_players(&self, group: &PlayerGroup, reference: &BTreeSet<Uuid>) -> BTreeSet<Uuid> {
+        let round_state = &self.round_state;
+        let flow = &round_state.flow;
+        let players = round_state.users_states.keys().copied();
+        match group {
+            PlayerGroup::Class(class) => players
+                .filter(|p| round_state.player_classes.get(p) == Some(class))
+                .collect(),
+            PlayerGroup::Position(ChainPosition::Retailer) => {
+                players.filter(|p| flow.is_sink(p)).collect()
+            }
+            PlayerGroup::Position(ChainPosition::Factory) => {
+                players.filter(|p| flow.is_source(p)).collect()
+            }
+            PlayerGroup::Position(ChainPosition::Upstream) => reference
+                .iter()
+                .flat_map(|p| flow.get_senders_weights(p))
+                .map(|(sender, _)| sender)
+                .filter(|sender| !sender.is_nil())
+                .collect(),
+            PlayerGroup::Position(ChainPosition::Downstream) => reference
+                .iter()
+                .flat_map(|p| flow.get_recipients(p))
+                .collect(),
+            PlayerGroup::Team(name) if round_state.team.name == *name => players.collect(),
+            PlayerGroup::Team(_) => BTreeSet::new(),
+        }
+    }
+
+    fn evaluate_cond(
+        &self,
+        event: &GameEvent,
+        last_users_states: Option<&BTreeMap<Uuid, UserState>>,
+    ) -> Result<(bool, Vec<Uuid>), AppError> {
+        let players = self.round_state.users_states.keys().copied().collect();
+        let (met, targets) =
+            self.evaluate_condition(&event.condition, &players, last_users_states)?;
+        Ok((met, targets.into_iter().collect()))
+    }
+
+    /// Evaluates a condition for the `checked` players with the ones it was met by,
+    /// nobody when it wasn't met. Conditions about the whole chain are met by all
+    /// checked players.
+    fn evaluate_condition(
+        &self,
+        condition: &EventCondition,
+        checked: &BTreeSet<Uuid>,
+        last_users_states: Option<&BTreeMap<Uuid, UserState>>,
+    ) -> Result<(bool, BTreeSet<Uuid>), AppError> {
+        let round_state = &self.round_state;
+        let users_states: Vec<(Uuid, &UserState)> = round_state
+            .users_states
+            .iter()
+            .filter(|(p, _)| checked.contains(p))
+            .map(|(p, us)| (*p, us))
+            .collect();
+        let (met, targets) = match condition {
+            EventCondition::RoundMet { round, compare } => {
+                let compare = compare.unwrap_or(Comparison::Equal);
+                evaluate_round_cond(round_state.round, checked, *round, compare)
+            }
+            EventCondition::ValueExceed {
+                resource,
+                met_by,
+                value,
+                compare,
+            } => evaluate_value_exceed(
+                resource_extractor(resource),
+                met_by,
+                &users_states,
+                *value,
+                *compare,
+            ),
+            EventCondition::SingleChange {
+                resource,
+                value,
+                compare,
+            } => {
+                let last_users_states = match last_users_states {
+                    Some(s) => s,
+                    None => {
+                        return Err(AppError::InternalServerError(
+                            "expected last round state for change event".to_string(),
+                        ))
+                    }
+                };
+
+                evaluate_single_change(
+                    resource_extractor(resource),
+                    &users_states,
+                    last_users_states,
+                    *value,
+                    compare.unwrap_or(Comparison::Greater),
+                )
+            }
+            EventCondition::And { conditions } => {
+                let mut targets: Vec<Uuid> = checked.iter().copied().collect();
+                for condition in conditions {
+                    let (_, met_by) =
+                        self.evaluate_condition(condition, checked, last_users_states)?;
+                    targets.retain(|p| met_by.contains(p));
+                }
+                (!targets.is_empty(), targets)
+            }
+            EventCondition::Or { conditions } => {
+                let mut targets = BTreeSet::new();
+                for condition in conditions {
+                    let (_, met_by) =
+                        self.evaluate_condition(condition, checked, last_users_states)?;
+                    targets.extend(met_by);
+                }
+                (!targets.is_empty(), targets.into_iter().collect())
+            }
+            EventCondition::Not { condition } => {
+                let (_, met_by) = self.evaluate_condition(condition, checked, last_users_states)?;
+                let targets: Vec<Uuid> = checked
+                    .iter()
+                    .filter(|p| !met_by.contains(p))
+                    .copied()
+                    .collect();
+                (!targets.is_empty(), targets)
+            }
+            EventCondition::Within { players, condition } => {
+                let group = self.group_players(players, checked);
+                let checked = checked.intersection(&group).copied().collect();
+                let (met, targets) =
+                    self.evaluate_condition(condition, &checked, last_users_states)?;
+                (met, targets.into_iter().collect())
+            }
+        };
+
+        if !met {
+            return Ok((false, BTreeSet::new()));
+        }
+        Ok((true, targets.into_iter().collect()))
+    }
+}
+
+/// Order of a player for a single product, with the prices of the product.
+struct ProductOrder {
+    product: Option<String>,
+    order: Order,
+    resource_price: i64,
+    magazine_cost: i64,
+}
+
+/// Goods and orders of a product passed on by a player in a round.
+struct StockMoves {
+    requested: i64,
+    send_order: Order,
+    deliveries: Vec<Order>,
+    split_orders: Vec<Order>,
+}
+
+/// A player with its neighbors in the flow.
+struct Links<'a> {
+    player: Uuid,
+    senders: &'a [(Uuid, i64)],
+    customers: &'a [Uuid],
+    /// Customers nothing can be shipped to this round.
+    blocked: &'a [Uuid],
+}
+
+impl Links<'_> {
+    /// Places the order for a product, takes in the incoming delivery and ships
+    /// what was requested. What the neighbors get is returned to be queued at them.
+    fn move_stock(
+        &self,
+        stock: &mut Stock,
+        mut placed_order: Order,
+        resource_price: i64,
+        fix_order_cost: i64,
+    ) -> Result<StockMoves, AppError> {
+        let player = self.player;
+        placed_order.recipient = player;
+        placed_order.sender = single_or_nil(self.senders.iter().map(|(s, _)| *s));
+        stock.placed_order = placed_order.clone();
+
+        let mut received_order = Order {
+            recipient: player,
+            sender: placed_order.sender,
+            ..Order::default()
+        };
+        for (sender, _) in self.senders {
+            match pop_front(&mut stock.incoming_orders, |o| o.sender == *sender) {
+                Some(io) => {
+                    received_order.value += io.value;
+                    received_order.cost += io.cost;
+                }
+                None => {
+                    return Err(AppError::InternalServerError(
+                        "expected a incoming order".to_string(),
+                    ))
+                }
+            }
+        }
+        stock.magazine_state += received_order.value;
+        stock.received_order = received_order;
+
+        let mut requested = Vec::with_capacity(self.customers.len());
+        for customer in self.customers {
+            match pop_front(&mut stock.requested_orders, |o| o.recipient == *customer) {
+                Some(ro) => requested.push(ro.value),
+                None => {
+                    return Err(AppError::InternalServerError(
+                        "expected a requested order".to_string(),
+                    ))
+                }
+            }
+        }
+
+        let shipped = ship_goods(stock, self.customers, &requested, self.blocked);
+        let send_order_val: i64 = shipped.iter().sum();
+        let send_order = Order {
+            recipient: single_or_nil(self.customers.iter().copied()),
+            sender: player,
+            value: send_order_val,
+            cost: send_order_val * resource_price + fix_order_cost,
+        };
+        stock.sent_orders.push(send_order.clone());
+
+        // goods and orders are queued at the neighbors right away, they are taken out
+        // after the delay so the order in which players finish doesn't matter
+        let mut deliveries = Vec::new();
+        for (customer, value) in self.customers.iter().zip(&shipped) {
+            if !customer.is_nil() {
+                deliveries.push(Order {
+                    recipient: *customer,
+                    sender: player,
+                    value: *value,
+                    cost: value * resource_price + fix_order_cost,
+                });
+            }
+        }
+
+        let weights: Vec<i64> = self.senders.iter().map(|(_, w)| *w).collect();
+        let parts = allocate(placed_order.value, &weights);
+        let mut split_orders = Vec::new();
+        for ((sender, _), value) in self.senders.iter().zip(parts) {
+            if !sender.is_nil() {
+                let cost = match placed_order.value {
+                    0 => 0,
+                    total => placed_order.cost * value / total,
+                };
+                split_orders.push(Order {
+                    recipient: player,
+                    sender: *sender,
+                    value,
+                    cost,
+                });
+            }
+        }
+
+        Ok(StockMoves {
+            requested: requested.iter().sum(),
+            send_order,
+            deliveries,
+            split_orders,
+        })
+    }
+}
+
+/// Ships as much of the backlog and the requested amounts as the magazine allows,
+/// split between customers in proportion to what they wait for. Whatever can't be
+/// shipped, also everything for the `blocked` customers, is added to the backlog.
+/// Returns the amount shipped to each customer.
+fn ship_goods(
+    user_state: &mut Stock,
+    customers: &[Uuid],
+    requested: &[i64],
+    blocked: &[Uuid],
+) -> Vec<i64> {
+    // backlog changed outside of shipping (events) is put on the first customer
+    let tracked: i64 = customers
+        .iter()
+        .filter_map(|c| user_state.back_orders.get(c))
+        .sum();
+    let untracked = user_state.back_order_sum - tracked;
+
+    let to_ship: Vec<i64> = customers
+        .iter()
+        .zip(requested)
+        .enumerate()
+        .map(|(i, (c, r))| {
+            let back_order = user_state.back_orders.get(c).copied().unwrap_or(0);
+            let back_order = if i == 0 {
+                back_order + untracked
+            } else {
+                back_order
+            };
+            back_order.max(0) + r
+        })
+        .collect();
+
+    let shippable: Vec<i64> = customers
+        .iter()
+        .zip(&to_ship)
+        .map(|(c, to_ship)| if blocked.contains(c) { 0 } else { *to_ship })
+        .collect();
+    let total: i64 = shippable.iter().sum();
+    let shipped = allocate(total.min(user_state.magazine_state).max(0), &shippable);
+
+    user_state.magazine_state -= shipped.iter().sum::<i64>();
+    user_state.back_orders.clear();
+    for ((customer, to_ship), shipped) in customers.iter().zip(&to_ship).zip(&shipped) {
+        user_state.back_orders.insert(*customer, to_ship - shipped);
+    }
+    user_state.back_order_sum = user_state.back_orders.values().sum();
+
+    shipped
+}
+
+/// Splits `amount` proportionally to `weights`, the rounding rest goes one by one
+/// to the first parts with a positive weight.
+fn allocate(amount: i64, weights: &[i64]) -> Vec<i64> {
+    let total: i64 = weights.iter().sum();
+    if total <= 0 {
+        return vec![0; weights.len()];
+    }
+
+    let mut parts: Vec<i64> = weights.iter().map(|w| amount * w / total).collect();
+    let mut rest = amount - parts.iter().sum::<i64>();
+    for (part, weight) in parts.iter_mut().zip(weights) {
+        if rest <= 0 {
+            break;
+        }
+        if *weight > 0 {
+            *part += 1;
+            rest -= 1;
+        }
+    }
+
+    parts
+}
+
+/// The only neighbor, or nil when there are none or several of them.
+fn single_or_nil(mut players: impl Iterator<Item = Uuid>) -> Uuid {
+    match (players.next(), players.next()) {
+        (Some(p), None) => p,
+        _ => Uuid::nil(),
+    }
+}
+
+fn push_order(
+    users_states: &mut BTreeMap<Uuid, UserState>,
+    player: Uuid,
+    product: Option<&str>,
+    order: Order,
+    queue: fn(&mut Stock) -> &mut Vec<Order>,
+) -> Result<(), AppError> {
+    match users_states
+        .get_mut(&player)
+        .and_then(|us| us.product_stock_mut(product))
+    {
+        Some(stock) => queue(stock).push(order),
+        None => {
+            return Err(AppError::InternalServerError(format!(
+                "not found recipient for order {:?}",
+                order
+            )))
+        }
+    }
+
+    Ok(())
+}
+
+fn update_performance(
+    user_state: &mut UserState,
+    metric: &PerformanceMetric,
+    requested: i64,
+    shipped: i64,
+) {
+    let served = back_order_total(user_state) == 0;
+    let stats = &mut user_state.performance_stats;
+    stats.requested += requested;
+    stats.shipped += shipped;
+    stats.rounds += 1;
+    if served {
+        stats.rounds_served += 1;
+    }
+
+    user_state.performance = match metric {
+        PerformanceMetric::FillRate => match stats.requested {
+            0 => 100,
+            requested => stats.shipped.min(requested) * 100 / requested,
+        },
+        PerformanceMetric::ServiceLevel => stats.rounds_served * 100 / stats.rounds,
+        PerformanceMetric::CostPerUnit => match stats.shipped {
+            0 => user_state.spent_money,
+            shipped => user_state.spent_money / shipped,
+        },
+    };
+}
+
+/// Orders queues are FIFO per link, an order pushed in a round is taken out after
+/// as many rounds as the queue of its link was long.
+fn pop_front(queue: &mut Vec<Order>, link: impl Fn(&Order) -> bool) -> Option<Order> {
+    let index = queue.iter().position(link)?;
+    Some(queue.remove(index))
+}
+
+/// Starting stock of a player, with full queues towards its neighbors and a full
+/// production line for factories.
+struct StartStock<'a> {
+    player: Uuid,
+    flow: &'a Flow,
+    lead_time: Option<usize>,
+}
+
+impl StartStock<'_> {
+    fn stock(
+        &self,
+        incoming: &[i64],
+        requested: &[i64],
+        demand: i64,
+        basic_price: i64,
+        magazine: i64,
+    ) -> Stock {
+        let player = self.player;
+        let mut incoming_orders = Vec::new();
+        for (sender, _) in self.flow.get_senders_weights(&player) {
+            incoming_orders.extend(incoming.iter().map(|value| Order {
+                recipient: player,
+                sender,
+                value: *value,
+                cost: basic_price * value,
+            }));
+        }
+
+        let mut requested_orders = Vec::new();
+        for recipient in self.flow.get_customers(&player) {
+            requested_orders.extend(requested.iter().map(|value| Order {
+                recipient,
+                sender: player,
+                value: *value,
+                cost: basic_price * value,
+            }));
+        }
+
+        Stock {
+            magazine_state: magazine,
+            incoming_orders,
+            requested_orders,
+            production: vec![demand; self.lead_time.unwrap_or(0)],
+            ..Stock::default()
+        }
+    }
+}
+
+/// Starting queue for a delay of `delay` rounds. A configured queue must match
+/// the delay, without one the queue is filled with `fill`.
+fn start_queue(
+    queue: Option<&Vec<i64>>,
+    delay: Option<&usize>,
+    fill: i64,
+    name: &str,
+) -> Result<Vec<i64>, AppError> {
+    match (queue, delay) {
+        (Some(q), Some(d)) if q.len() != *d => Err(AppError::BadRequest(format!(
+            "{} start queue has {} orders, expected {} for the {} delay",
+            name,
+            q.len(),
+            d,
+            name
+        ))),
+        (Some(q), _) if q.is_empty() => Err(AppError::BadRequest(format!(
+            "{} start queue can't be empty",
+            name
+        ))),
+        (Some(q), _) => Ok(q.clone()),
+        (None, Some(0)) => Err(AppError::BadRequest(format!(
+            "{} delay has to be at least one round",
+            name
+        ))),
+        (None, Some(d)) => Ok(vec![fill; *d]),
+        (None, None) => Err(AppError::BadRequest(format!(
+            "no {} delay or start queue for player class",
+            name
+        ))),
+    }
+}
+
+fn class_value(map: &BTreeMap<u32, i64>, class: u32, name: &str) -> Result<i64, AppError> {
+    match map.get(&class) {
+        Some(v) => Ok(*v),
+        None => Err(AppError::BadRequest(format!(
+            "player-{} not found for class {}",
+            name, class
+        ))),
+    }
+}
+
+/// Applies the warehouse limits to the stock left after shipping: the part above
+/// the capacity is thrown away or charged for, then some of it spoils. Products
+/// share the warehouse, each loses its part of the excess. Returns the surcharge
+/// to pay.
+fn store_goods(
+    user_state: &mut UserState,
+    capacity: Option<i64>,
+    overflow: &WarehouseOverflow,
+    spoilage_rate: i64,
+) -> i64 {
+    let stocks: Vec<i64> = user_state
+        .stocks()
+        .map(|s| s.magazine_state.max(0))
+        .collect();
+    let excess = match capacity {
+        Some(c) => (stocks.iter().sum::<i64>() - c).max(0),
+        None => 0,
+    };
+
+    let (rejected, surcharge) = match overflow {
+        WarehouseOverflow::Reject => (allocate(excess, &stocks), 0),
+        WarehouseOverflow::Surcharge { cost } => (vec![0; stocks.len()], excess * cost),
+    };
+
+    for (stock, rejected) in user_state.stocks_mut().zip(rejected) {
+        stock.rejected = rejected;
+        stock.magazine_state -= rejected;
+        stock.spoiled = stock.magazine_state.max(0) * spoilage_rate / 100;
+        stock.magazine_state -= stock.spoiled;
+    }
+
+    surcharge
+}
+
+fn back_order_total(user_state: &UserState) -> i64 {
+    user_state.stocks().map(|s| s.back_order_sum).sum()
+}
+
+fn validate_warehouse(settings: &Settings) -> Result<(), AppError> {
+    if settings.warehouse_capacity.values().any(|c| *c < 0) {
+        return Err(AppError::BadRequest(
+            "negative warehouse capacity".to_string(),
+        ));
+    }
+    if settings
+        .spoilage_rate
+        .values()
+        .any(|r| !(0..=100).contains(r))
+    {
+        return Err(AppError::BadRequest(
+            "spoilage rate has to be between 0 and 100".to_string(),
+        ));
+    }
+    if let WarehouseOverflow::Surcharge { cost } = settings.warehouse_overflow {
+        if cost < 0 {
+            return Err(AppError::BadRequest("negative surcharge".to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether the player manufactures the goods it gets from the supply.
+fn is_producer(round_state: &RoundState, player: Uuid, class: u32) -> bool {
+    round_state
+        .settings
+        .production_capacity
+        .contains_key(&class)
+        && round_state.flow.is_source(&player)
+}
+
+/// Pushes the customer demand for a product to the chain sinks.
+fn push_demand(
+    round_state: &mut RoundState,
+    product: Option<&str>,
+    sinks: &[Uuid],
+    value: i64,
+    basic_price: i64,
+) -> Result<(), AppError> {
+    for sink in sinks {
+        let order = Order {
+            recipient: Uuid::nil(),
+            sender: *sink,
+            value,
+            cost: basic_price * value,
+        };
+        push_order(&mut round_state.users_states, *sink, product, order, |s| {
+            &mut s.requested_orders
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Delivers the supply of a product to the chain sources. On a shortage the
+/// capacity is shared in proportion to their orders.
+fn push_supply(
+    round_state: &mut RoundState,
+    product: Option<&str>,
+    sources: &[Uuid],
+    capacity: i64,
+    basic_price: i64,
+) -> Result<Supply, AppError> {
+    let mut ordered = Vec::with_capacity(sources.len());
+    for source in sources {
+        match round_state
+            .users_states
+            .get(source)
+            .and_then(|us| us.product_stock(product))
+        {
+            Some(stock) => ordered.push(stock.placed_order.value),
+            None => {
+                return Err(AppError::InternalServerError(
+                    "not found fist player order".to_string(),
+                ))
+            }
+        };
+    }
+
+    let total_ordered: i64 = ordered.iter().sum();
+    let delivered = if total_ordered <= capacity {
+        ordered
+    } else {
+        allocate(capacity.max(0), &ordered)
+    };
+    let supplied: i64 = delivered.iter().sum();
+    for (source, value) in sources.iter().zip(delivered) {
+        let value = match round_state.player_classes.get(source) {
+            Some(class) if is_producer(round_state, *source, *class) => {
+                produce(round_state, *source, product, *class, value)?
+            }
+            _ => value,
+        };
+        let order = Order {
+            recipient: *source,
+            sender: Uuid::nil(),
+            value,
+            cost: basic_price * value,
+        };
+        push_order(
+            &mut round_state.users_states,
+            *source,
+            product,
+            order,
+            |s| &mut s.incoming_orders,
+        )?;
+    }
+
+    Ok(Supply {
+        capacity,
+        ordered: total_ordered,
+        delivered: supplied,
+    })
+}
+
+/// Starts manufacturing the raw material supplied to `player`, returns the batch
+/// finished this round. Nothing is finished until the lead time is filled.
+fn produce(
+    round_state: &mut RoundState,
+    player: Uuid,
+    product: Option<&str>,
+    class: u32,
+    supplied: i64,
+) -> Result<i64, AppError> {
+    let lead_time = round_state
+        .settings
+        .production_lead_time
+        .get(&class)
+        .copied()
+        .unwrap_or(0);
+    let stock = match round_state
+        .users_states
+        .get_mut(&player)
+        .and_then(|us| us.product_stock_mut(product))
+    {
+        Some(s) => s,
+        None => {
+            return Err(AppError::InternalServerError(
+                "expected a user state".to_string(),
+            ))
+        }
+    };
+
+    stock.production.push(supplied);
+    if stock.production.len() > lead_time {
+        Ok(stock.production.remove(0))
+    } else {
+        Ok(0)
+    }
+}
+
+fn active_disruptions(round_state: &RoundState) -> impl Iterator<Item = &EventAction> {
+    round_state
+        .disruptions
+        .iter()
+        .filter(|d| d.is_active(round_state.round))
+        .map(|d| &d.action)
+}
+
+/// Production capacity of the class lowered by the active cuts.
+fn production_capacity(round_state: &RoundState, class: u32) -> Option<i64> {
+    let capacity = *round_state.settings.production_capacity.get(&class)?;
+    Some(
+        active_disruptions(round_state)
+            .fold(capacity, |capacity, action| match action {
+                EventAction::CutProductionCapacity {
+                    class: cut_class,
+                    percent,
+                    ..
+                } if *cut_class == class => capacity * (100 - percent) / 100,
+                _ => capacity,
+            })
+            .max(0),
+    )
+}
+
+/// Percent of the generated demand the customers ask for.
+fn demand_percent(round_state: &RoundState) -> i64 {
+    active_disruptions(round_state).fold(100, |total, action| match action {
+        EventAction::ScaleDemand { percent, .. } => total * (*percent).max(0) / 100,
+        _ => total,
+    })
+}
+
+fn blocked_customers(round_state: &RoundState, player: Uuid, class: u32) -> Vec<Uuid> {
+    round_state
+        .flow
+        .get_customers(&player)
+        .into_iter()
+        .filter(|customer| {
+            let customer_class = round_state.player_classes.get(customer);
+            active_disruptions(round_state).any(|action| {
+                matches!(action, EventAction::BlockShipments { link, .. }
+                    if link.sender == class && Some(&link.recipient) == customer_class)
+            })
+        })
+        .collect()
+}
+
+/// Holds back a delivery on a delayed link and adds the goods held back before
+/// that are due. Returns what reaches the recipient's queue this round.
+fn delay_delivery(
+    round_state: &mut RoundState,
+    product: Option<&str>,
+    sender_class: u32,
+    mut order: Order,
+) -> Order {
+    let round = round_state.round;
+    let recipient_class = round_state.player_classes.get(&order.recipient);
+    let delay = active_disruptions(round_state)
+        .filter_map(|action| match action {
+            EventAction::DelayShipments { link, delay, .. }
+                if link.sender == sender_class && Some(&link.recipient) == recipient_class =>
+            {
+                Some(*delay)
+            }
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+
+    if delay > 0 {
+        round_state.delayed_shipments.push(DelayedShipment {
+            release: round + delay,
+            product: product.map(str::to_string),
+            order: order.clone(),
+        });
+        order.value = 0;
+        order.cost = 0;
+    }
+
+    let (released, held): (Vec<DelayedShipment>, Vec<DelayedShipment>) =
+        std::mem::take(&mut round_state.delayed_shipments)
+            .into_iter()
+            .partition(|s| {
+                s.release <= round
+                    && s.order.sender == order.sender
+                    && s.order.recipient == order.recipient
+                    && s.product.as_deref() == product
+            });
+    round_state.delayed_shipments = held;
+    for shipment in released {
+        order.value += shipment.order.value;
+        order.cost += shipment.order.cost;
+    }
+
+    order
+}
+
+fn optional_class_value(map: &BTreeMap<u32, i64>, class: u32) -> i64 {
+    map.get(&class).copied().unwrap_or(0)
+}
+
+fn add_resource(player_state: &mut UserState, resource: &Resource, value: i64) {
+    match resource {
+        Resource::Money => player_state.money += value,
+        Resource::MagazineState => player_state.stock.magazine_state += value,
+        Resource::Performance => player_state.performance += value,
+        Resource::BackOrderValue => player_state.stock.back_order_sum += value,
+    }
+}
+
+fn resource_extractor(resource: &Resource) -> fn(&UserState) -> i64 {
+    match resource {
+        Resource::Money => |us| us.money,
+        Resource::MagazineState => |us| us.stock.magazine_state,
+        Resource::Performance => |us| us.performance,
+        Resource::BackOrderValue => |us| us.stock.back_order_sum,
+    }
+}
+
+fn evaluate_round_cond(
+    current_round: i64,
+    players: &BTreeSet<Uuid>,
+    round: i64,
+    compare: Comparison,
+) -> (bool, Vec<Uuid>) {
+    tracing::debug!("assesing round cond, {}, {}", current_round, round);
+    let mut players_id = Vec::new();
+    if compare.holds(current_round, round) {
+        players_id.extend(players);
+    }
+    (!players_id.is_empty(), players_id)
+}
+
+//TODO: refactor name
+fn evaluate_value_exceed(
+    extractor: fn(&UserState) -> i64,
+    met_by: &MetBy,
+    users_states: &[(Uuid, &UserState)],
+    value: i64,
+    compare: Option<Comparison>,
+) -> (bool, Vec<Uuid>) {
+    let mut recipients = Vec::new();
+    let met = match met_by {
+        MetBy::SinglePlayer => {
+            let compare = compare.unwrap_or(Comparison::Greater);
+            for (u_id, user_state) in users_states {
+                if compare.holds(extractor(user_state), value) {
+                    recipients.push(*u_id)
+                }
+            }
+            !recipients.is_empty()
+        }
+        MetBy::Average => {
+            if users_states.is_empty() {
+                return (false, recipients);
+            }
+
+            let mut sum = 0;
+            for (u_id, user_state) in users_states {
+                sum += extractor(user_state);
+                recipients.push(*u_id);
+            }
+            let average = sum / users_states.len() as i64;
+            compare.unwrap_or(Comparison::Greater).holds(average, value)
+        }
+        MetBy::AllPlayers => {
+            let compare = compare.unwrap_or(Comparison::GreaterOrEqual);
+            let mut val_met = true;
+            for (u_id, user_state) in users_states {
+                if !compare.holds(extractor(user_state), value) {
+                    val_met = false;
+                    break;
+                } else {
+                    recipients.push(*u_id);
+                }
+            }
+            val_met
+        }
+    };
+    (met, recipients)
+}
+
+fn evaluate_single_change(
+    extractor: fn(&UserState) -> i64,
+    users_states: &[(Uuid, &UserState)],
+    last_users_states: &BTreeMap<Uuid, UserState>,
+    value: i64,
+    compare: Comparison,
+) -> (bool, Vec<Uuid>) {
+    let mut recipients = Vec::new();
+
+    for (u_id, user_state) in users_states {
+        let last_user_state = match last_users_states.get(u_id) {
+            Some(s) => s,
+            None => continue, //user disconnected probably
+        };
+
+        let change = (extractor(user_state) - extractor(last_user_state)).abs();
+        if compare.holds(change, value) {
+            recipients.push(*u_id)
+        }
+    }
+
+    (!recipients.is_empty(), recipients)
+}
+
+/// Puts lobby players in their chain positions. Every player needs a class and an
+/// explicit order has to list each lobby player exactly once.
+pub fn order_players(
+    mut players: Vec<Uuid>,
+    order: &PlayerOrder,
+    player_classes: &BTreeMap<Uuid, u32>,
+    rng: &mut impl Rng,
+) -> Result<Vec<Uuid>, AppError> {
+    let mut classes = Vec::with_capacity(players.len());
+    for player in &players {
+        match player_classes.get(player) {
+            Some(c) => classes.push((*player, *c)),
+            None => {
+                return Err(AppError::BadRequest(format!(
+                    "no class for player {}",
+                    player
+                )))
+            }
+        }
+    }
+
+    match order {
+        PlayerOrder::Lobby => {}
+        PlayerOrder::Explicit { players: ordered } => {
+            let mut expected = players.clone();
+            let mut given = ordered.clone();
+            expected.sort();
+            given.sort();
+            if expected != given {
+                return Err(AppError::BadRequest(
+                    "players order has to list every lobby player once".to_string(),
+                ));
+            }
+            players = ordered.clone();
+        }
+        PlayerOrder::ByClass { classes: positions } => {
+            let mut positioned = Vec::with_capacity(classes.len());
+            for (player, class) in classes {
+                match positions.iter().position(|c| *c == class) {
+                    Some(p) => positioned.push((p, player)),
+                    None => {
+                        return Err(AppError::BadRequest(format!(
+                            "no position for class {}",
+                            class
+                        )))
+                    }
+                }
+            }
+            positioned.sort_by_key(|(position, _)| *position);
+            players = positioned.into_iter().map(|(_, player)| player).collect();
+        }
+        PlayerOrder::Shuffle => players.shuffle(rng),
+    }
+
+    Ok(players)
+}
+
+const DEMAND_STREAM: u64 = 0;
+const SUPPLY_STREAM: u64 = 1;
+/// Every bot draws from its own stream, offset by its id, past the ones of the
+/// products.
+const BOT_STREAM: u64 = 1 << 32;
+
+/// Random numbers for a round of a game, the same for every team playing with the
+/// seed no matter how many were drawn before.
+pub fn round_rng(seed: u64, round: i64, stream: u64) -> ChaCha8Rng {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    rng.set_stream(stream);
+    rng.set_word_pos(round as u128 * 1024);
+    rng
+}
+
+pub fn start_value(style: &GeneratedOrderStyle) -> Result<i64, AppError> {
+    let value = match style {
+        GeneratedOrderStyle::Default => 10,
+        GeneratedOrderStyle::Linear { start, increase: _ } => *start,
+        GeneratedOrderStyle::Multiplication { start, increase: _ } => *start,
+        GeneratedOrderStyle::Exponential {
+            start,
+            power: _,
+            modulator: _,
+        } => *start,
+        GeneratedOrderStyle::List { list, after_end: _ } => match list.first() {
+            Some(d) => *d,
+            None => return Err(AppError::BadRequest("bad list demand".to_string())),
+        },
+        GeneratedOrderStyle::Normal { mean, std_dev } => {
+            if *std_dev < 0 {
+                return Err(AppError::BadRequest(
+                    "negative standard deviation".to_string(),
+                ));
+            }
+            *mean
+        }
+        GeneratedOrderStyle::Poisson { mean } => {
+            if *mean <= 0 {
+                return Err(AppError::BadRequest(
+                    "poisson mean must be positive".to_string(),
+                ));
+            }
+            *mean
+        }
+        GeneratedOrderStyle::Uniform { min, max } => {
+            if min > max {
+                return Err(AppError::BadRequest("uniform min above max".to_string()));
+            }
+            (min + max) / 2
+        }
+        GeneratedOrderStyle::StepChange {
+            before,
+            after: _,
+            round: _,
+            noise,
+        } => {
+            if *noise < 0 {
+                return Err(AppError::BadRequest(
+                    "negative standard deviation".to_string(),
+                ));
+            }
+            *before
+        }
+        GeneratedOrderStyle::Seasonal {
+            base,
+            amplitude,
+            period,
+        } => {
+            if *period <= 0 {
+                return Err(AppError::BadRequest(
+                    "seasonal period must be positive".to_string(),
+                ));
+            }
+            if *amplitude < 0 || amplitude > base {
+                return Err(AppError::BadRequest(
+                    "seasonal amplitude must be between 0 and base".to_string(),
+                ));
+            }
+            *base
+        }
+        GeneratedOrderStyle::Promotion { base, promotions } => {
+            for promotion in promotions {
+                if promotion.from > promotion.to {
+                    return Err(AppError::BadRequest(
+                        "promotion ends before it starts".to_string(),
+                    ));
+                }
+                if base + promotion.increase < 0 {
+                    return Err(AppError::BadRequest(
+                        "promotion makes demand negative".to_string(),
+                    ));
+                }
+            }
+            promotion_value(*base, promotions, 0)
+        }
+        GeneratedOrderStyle::Piecewise { segments } => {
+            let mut next_round = 0;
+            for segment in segments {
+                if segment.from != next_round || segment.to < segment.from {
+                    return Err(AppError::BadRequest(format!(
+                        "demand segments must cover rounds from 0 without gaps, bad segment at round {}",
+                        segment.from
+                    )));
+                }
+                if segment.value < 0 {
+                    return Err(AppError::BadRequest("negative demand segment".to_string()));
+                }
+                next_round = segment.to + 1;
+            }
+            match segments.first() {
+                Some(s) => s.value,
+                None => return Err(AppError::BadRequest("no demand segments".to_string())),
+            }
+        }
+    };
+
+    Ok(value)
+}
+
+/// Demand of `round`, random styles draw from `rng`.
+pub fn generate_demand(
+    last_demand: i64,
+    demand_style: &GeneratedOrderStyle,
+    round: i64,
+    rng: &mut impl Rng,
+) -> i64 {
+    match demand_style {
+        GeneratedOrderStyle::Default => (last_demand as f64 * 1.5) as i64,
+        GeneratedOrderStyle::Linear { start: _, increase } => last_demand + increase,
+        GeneratedOrderStyle::Multiplication { start: _, increase } => last_demand * increase,
+        GeneratedOrderStyle::Exponential {
+            start: _,
+            power,
+            modulator,
+        } => last_demand * (modulator * (E.powi(*power as i32)) as i64),
+        GeneratedOrderStyle::List { list, after_end } => {
+            let round = round.max(0) as usize;
+            let value = match after_end {
+                _ if round < list.len() => list.get(round),
+                ListEnd::Cycle if !list.is_empty() => list.get(round % list.len()),
+                _ => list.last(),
+            };
+
+            value.copied().unwrap_or(last_demand)
+        }
+        GeneratedOrderStyle::Normal { mean, std_dev } => normal_sample(*mean, *std_dev, rng),
+        GeneratedOrderStyle::Poisson { mean } => match Poisson::new(*mean as f64) {
+            Ok(poisson) => poisson.sample(rng) as i64,
+            Err(_) => last_demand,
+        },
+        GeneratedOrderStyle::Uniform { min, max } => {
+            if min > max {
+                return last_demand;
+            }
+            rng.gen_range(*min..=*max).max(0)
+        }
+        GeneratedOrderStyle::StepChange {
+            before,
+            after,
+            round: change_round,
+            noise,
+        } => {
+            let mean = if round < *change_round { before } else { after };
+            normal_sample(*mean, *noise, rng)
+        }
+        GeneratedOrderStyle::Seasonal {
+            base,
+            amplitude,
+            period,
+        } => {
+            if *period <= 0 {
+                return *base;
+            }
+            let angle = 2.0 * PI * round as f64 / *period as f64;
+            (*base as f64 + *amplitude as f64 * angle.sin())
+                .round()
+                .max(0.0) as i64
+        }
+        GeneratedOrderStyle::Promotion { base, promotions } => {
+            promotion_value(*base, promotions, round)
+        }
+        GeneratedOrderStyle::Piecewise { segments } => segments
+            .iter()
+            .find(|s| s.from <= round && round <= s.to)
+            .or_else(|| segments.last())
+            .map_or(last_demand, |s| s.value),
+    }
+}
+
+/// Whether a schedule that ends the game, of the main product or any other, has no
+/// demand left for `round`.
+fn demand_ended(settings: &Settings, round: i64) -> bool {
+    let schedule_ended = |demand_style: &GeneratedOrderStyle| match demand_style {
+        GeneratedOrderStyle::List {
+            list,
+            after_end: ListEnd::EndGame,
+        } => round >= list.len() as i64,
+        _ => false,
+    };
+    schedule_ended(&settings.demand_style)
+        || settings
+            .products
+            .iter()
+            .any(|product| schedule_ended(&product.demand_style))
+}
+
+fn promotion_value(base: i64, promotions: &[DemandPromotion], round: i64) -> i64 {
+    let increase: i64 = promotions
+        .iter()
+        .filter(|p| p.from <= round && round <= p.to)
+        .map(|p| p.increase)
+        .sum();
+    (base + increase).max(0)
+}
+
+fn normal_sample(mean: i64, std_dev: i64, rng: &mut impl Rng) -> i64 {
+    match Normal::new(mean as f64, std_dev as f64) {
+        Ok(normal) => (normal.sample(rng).round() as i64).max(0),
+        Err(_) => mean.max(0),
+    }
+}
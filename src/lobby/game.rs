@@ -1,26 +1,27 @@
 use std::{
-    collections::{BTreeMap, HashMap},
-    f64::consts::E,
+    collections::{BTreeMap, BTreeSet, HashMap},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use serde::{Deserialize, Serialize};
-use sqlx::{PgPool, Postgres, Transaction};
+use sqlx::{Executor, PgPool, Postgres, Transaction};
 use uuid::Uuid;
 
 use crate::{
     entities::{
-        ActionTarget, EventAction, EventCondition, Flow, GameState, GeneratedOrderStyle, Lobby,
-        MetBy, Order, Resource, Settings, User, UserState,
+        BotPolicy, DelayedShipment, Disruption, Flow, GameState, Lobby, Order, ProductRound,
+        Settings, Supply, Team, User, UserState, Visibility,
     },
     error::AppError,
     websockets::EventMessages,
-    LobbyState, RoundState, State,
+    RoundState, State,
 };
 
 use super::{
+    engine::GameEngine,
     lobby::{get_lobby, send_broadcast_msg},
-    stats::{get_player_stats, UserStats, UserStatsType},
+    stats::{get_player_stats, get_teams_stats, UserStatsType},
 };
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
@@ -32,577 +33,476 @@ pub struct GameUpdate {
     pub round_orders: BTreeMap<Uuid, Order>,
     pub send_orders: BTreeMap<Uuid, Order>,
     pub player_classes: BTreeMap<Uuid, u32>,
+    #[serde(default)]
+    pub team: Team,
+    #[serde(default)]
+    pub supply: Supply,
+    /// Demand and supply of the additional products.
+    #[serde(default)]
+    pub products: BTreeMap<String, ProductRound>,
+    /// Seed of the random demand, passing it to a new game replays this one. Only
+    /// users outside of the chain get it.
+    #[serde(default)]
+    pub seed: Option<u64>,
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
-pub struct GameEnd {
-    pub player_states: BTreeMap<Uuid, UserState>,
-    pub stats: HashMap<String, HashMap<Uuid, Vec<i64>>>,
+impl GameUpdate {
+    /// Part of the update the player is allowed to see with the game visibility.
+    /// Users outside of the chain, like the lobby owner, see everything.
+    pub fn visible_to(mut self, player: Uuid) -> GameUpdate {
+        if !self.player_states.contains_key(&player) {
+            return self;
+        }
+        // the seed gives away the coming demand
+        self.seed = None;
+
+        let visible = match visible_players(&self.settings.visibility, &self.flow, player) {
+            Some(visible) => visible,
+            None => return self,
+        };
+
+        // only the ends of the chain see the customers and the supply
+        let sees_demand =
+            self.settings.visibility == Visibility::PointOfSale || self.flow.is_sink(&player);
+        let sees_supply = self.flow.is_source(&player);
+
+        self.flow.links.retain(|l| {
+            l.sender == player
+                || l.recipient == player
+                || (visible.contains(&l.sender) && visible.contains(&l.recipient))
+        });
+        let mut known = visible.clone();
+        for link in &self.flow.links {
+            known.insert(link.sender);
+            known.insert(link.recipient);
+        }
+
+        self.player_states.retain(|p, _| visible.contains(p));
+        self.player_classes.retain(|p, _| known.contains(p));
+        // nil keys hold the customer demand and the supply
+        self.round_orders
+            .retain(|p, _| visible.contains(p) || (p.is_nil() && sees_demand));
+        self.send_orders
+            .retain(|p, _| visible.contains(p) || (p.is_nil() && sees_supply));
+        if !sees_supply {
+            self.supply = Supply::default();
+        }
+        for product in self.products.values_mut() {
+            if !sees_demand {
+                product.demand = 0;
+            }
+            if !sees_supply {
+                product.supply = Supply::default();
+            }
+        }
+
+        self
+    }
 }
 
-#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
-pub struct UserEndRound {
-    pub placed_order: Order,
+impl GameEnd {
+    /// Part of the game end with only the `visible` players, as given by
+    /// `visible_players`.
+    pub fn visible_to(mut self, visible: &BTreeSet<Uuid>) -> GameEnd {
+        self.player_states.retain(|p, _| visible.contains(p));
+        for players in self.stats.values_mut() {
+            players.retain(|p, _| visible.contains(p));
+        }
+        self
+    }
 }
 
-pub async fn process_user_round_end_message(
-    game_id: Uuid,
+/// Players of the chain whose state `player` sees, `None` when it sees all of them.
+pub fn visible_players(
+    visibility: &Visibility,
+    flow: &Flow,
     player: Uuid,
-    mut msg: UserEndRound,
-    state: Arc<State>,
-    db: &PgPool,
-) -> Result<(), AppError> {
-    //TODO: rewrite to if with early exit
-    tracing::debug!("process_user_round_end_message: {}", game_id);
-    let mut round_state;
-
-    match state.lobbies.write().await.get(&game_id) {
-        Some(lb) => {
-            round_state = lb.round_state.clone();
-        }
-        None => {
-            return Err(AppError::InternalServerError(
-                "expected a lobby state".to_string(),
-            ))
+) -> Option<BTreeSet<Uuid>> {
+    let mut visible = BTreeSet::from([player]);
+    match visibility {
+        Visibility::Full => return None,
+        Visibility::Neighbors => {
+            for link in &flow.links {
+                if link.sender == player {
+                    visible.insert(link.recipient);
+                } else if link.recipient == player {
+                    visible.insert(link.sender);
+                }
+            }
         }
+        Visibility::Own | Visibility::PointOfSale => {}
     }
+    Some(visible)
+}
 
-    tracing::debug!(
-        "got lobby_state for process_user_round_end_message: {}",
-        game_id
-    );
+/// Deadlines of the running team rounds and the time left of the rounds frozen
+/// by a pause.
+#[derive(Debug, Default)]
+pub struct RoundTimers {
+    /// Bumped when the timers are frozen, older timers don't close rounds.
+    generation: u64,
+    running: BTreeMap<Uuid, (i64, Instant)>,
+    frozen: BTreeMap<Uuid, (i64, u64)>,
+}
 
-    let player_class;
-    match round_state.player_classes.get(&player) {
-        Some(c) => player_class = c,
-        None => {
-            return Err(AppError::BadRequest(
-                "class for player not found".to_string(),
-            ))
-        }
+impl RoundTimers {
+    /// Drops every timer, the rounds get new ones when they start again.
+    fn reset(&mut self) {
+        self.generation += 1;
+        self.running.clear();
+        self.frozen.clear();
     }
 
-    let resource_price = match round_state.settings.resource_price.get(&player_class) {
-        Some(c) => c,
-        None => {
-            return Err(AppError::BadRequest(
-                "player-resource price not found".to_string(),
-            ))
+    fn freeze(&mut self) {
+        let now = Instant::now();
+        self.generation += 1;
+        for (team, (round, ends)) in std::mem::take(&mut self.running) {
+            let left = ends.saturating_duration_since(now).as_secs();
+            self.frozen.insert(team, (round, left));
         }
-    };
+    }
+}
 
-    let fix_order_cost = match round_state.settings.fix_order_cost.get(&player_class) {
-        Some(c) => c,
-        None => {
-            return Err(AppError::BadRequest(
-                "player-fix_order_cost not found".to_string(),
-            ))
-        }
-    };
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct GameEnd {
+    pub player_states: BTreeMap<Uuid, UserState>,
+    pub stats: HashMap<String, HashMap<Uuid, Vec<i64>>>,
+    /// Same stats summed up for every team.
+    #[serde(default)]
+    pub teams_stats: HashMap<String, HashMap<Uuid, Vec<i64>>>,
+}
 
-    let magazine_cost = match round_state.settings.magazine_cost.get(&player_class) {
-        Some(c) => c,
-        None => {
-            return Err(AppError::BadRequest(
-                "player-magazine_cost not found".to_string(),
-            ))
-        }
-    };
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct UserEndRound {
+    pub placed_order: Order,
+    /// Orders for the additional products, by name.
+    #[serde(default)]
+    pub product_orders: BTreeMap<String, Order>,
+}
 
-    tracing::debug!("about to process orders: {}", game_id);
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct StartGame {
+    pub player_classes: BTreeMap<Uuid, u32>,
+    /// Supply chain of the game, players are put in a chain when not given.
+    #[serde(default)]
+    pub flow: Option<Flow>,
+    #[serde(default)]
+    pub order: PlayerOrder,
+    /// Chains played in parallel, all players form a single chain when empty.
+    #[serde(default)]
+    pub teams: Vec<TeamSetup>,
+    /// Positions played by bots, either replacing a lobby player or joining with
+    /// a new id.
+    #[serde(default)]
+    pub bots: BTreeMap<Uuid, BotSetup>,
+    /// Seed of the random demand, a stored one replays a game. Drawn when not given.
+    #[serde(default)]
+    pub seed: Option<u64>,
+}
 
-    match round_state.users_states.get_mut(&player) {
-        Some(user_state) => {
-            if msg.placed_order.cost > user_state.money {
-                //TODO: send msg here ?
-                return Err(AppError::BadOrder(
-                    "not enough money for placed order".to_string(),
-                ));
-            }
+/// Body of the start request, older clients send only the player classes.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum StartGameBody {
+    Legacy(BTreeMap<Uuid, u32>),
+    Full(StartGame),
+}
 
-            tracing::debug!("sending ack: {}", game_id);
-            send_broadcast_msg(&state, game_id, EventMessages::Ack(player)).await?;
+impl From<StartGameBody> for StartGame {
+    fn from(body: StartGameBody) -> Self {
+        match body {
+            StartGameBody::Legacy(player_classes) => StartGame {
+                player_classes,
+                flow: None,
+                order: PlayerOrder::default(),
+                teams: Vec::new(),
+                bots: BTreeMap::new(),
+                seed: None,
+            },
+            StartGameBody::Full(start) => start,
+        }
+    }
+}
 
-            tracing::debug!("send ack, processing orders");
-            //for multiple recipients
-            msg.placed_order.recipient = player;
-            msg.placed_order.sender = round_state.flow.get_sender(&player)?;
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct BotSetup {
+    /// Needed for bots joining the game.
+    #[serde(default)]
+    pub class: Option<u32>,
+    pub policy: BotPolicy,
+}
 
-            user_state.money -= msg.placed_order.cost;
-            user_state.spent_money += msg.placed_order.cost;
-            user_state.placed_order = msg.placed_order.clone();
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct TeamSetup {
+    pub name: String,
+    pub players: Vec<Uuid>,
+    #[serde(default)]
+    pub flow: Option<Flow>,
+    #[serde(default)]
+    pub order: PlayerOrder,
+}
 
-            let magazine_cost = user_state.magazine_state * magazine_cost;
-            user_state.money -= magazine_cost;
-            user_state.spent_money += magazine_cost;
+/// Positions of players in the chain, from the first supplier to the retailer.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Default)]
+#[serde(tag = "type")]
+pub enum PlayerOrder {
+    /// Order in which players are listed in the lobby.
+    #[default]
+    Lobby,
+    Explicit {
+        players: Vec<Uuid>,
+    },
+    /// Players are placed by their class, classes listed from upstream to downstream.
+    ByClass {
+        classes: Vec<u32>,
+    },
+    Shuffle,
+}
 
-            round_state
-                .round_orders
-                .insert(player, msg.placed_order.clone());
+pub async fn process_user_round_end_message(
+    game_id: Uuid,
+    player: Uuid,
+    msg: UserEndRound,
+    state: Arc<State>,
+    db: &PgPool,
+) -> Result<(), AppError> {
+    tracing::debug!("process_user_round_end_message: {}", game_id);
 
-            tracing::debug!("about to process incoming orders: {}", game_id);
-            if let Some(io) = user_state.incoming_orders.pop() {
-                user_state.magazine_state += io.value;
-                user_state.received_order = io;
-            } else {
-                return Err(AppError::InternalServerError(
-                    "expected a incoming order".to_string(),
-                ));
+    let (team, round_finished, events) = match state.lobbies.write().await.get_mut(&game_id) {
+        Some(lobby_state) => {
+            if lobby_state.paused {
+                return Err(AppError::GamePaused(game_id.to_string()));
             }
 
-            tracing::debug!("about to process requested orders: {}", game_id);
-            if let Some(ro) = user_state.requested_orders.pop() {
-                let mut send_order_val = 0;
-
-                if user_state.back_order_sum > user_state.magazine_state {
-                    send_order_val = user_state.magazine_state;
-                    user_state.back_order_sum -= user_state.magazine_state;
-                    user_state.magazine_state = 0;
-                } else if user_state.back_order_sum > 0 {
-                    user_state.magazine_state -= user_state.back_order_sum;
-                    send_order_val = user_state.back_order_sum;
-                    user_state.back_order_sum = 0;
+            let team = match lobby_state.team_of(&player) {
+                Some(t) => t,
+                None => {
+                    return Err(AppError::BadRequest(
+                        "player is not in any team".to_string(),
+                    ))
                 }
-
-                if user_state.magazine_state > ro.value {
-                    user_state.magazine_state -= ro.value;
-                    send_order_val += ro.value;
-                } else {
-                    let diff = ro.value - user_state.magazine_state;
-                    user_state.magazine_state = 0;
-                    user_state.back_order_sum += diff;
-                    send_order_val += diff;
+            };
+            let round_state = match lobby_state.teams.get_mut(&team) {
+                Some(r) => r,
+                None => {
+                    return Err(AppError::InternalServerError(
+                        "expected a team state".to_string(),
+                    ))
                 }
+            };
 
-                let send_order_val_cost = send_order_val * resource_price + fix_order_cost;
-
-                let send_order = Order {
-                    recipient: round_state.flow.get_recipient(&player)?,
-                    sender: player,
-                    value: send_order_val,
-                    cost: send_order_val_cost,
-                };
-
-                round_state.send_orders.insert(player, send_order.clone());
-
-                user_state.sent_orders.push(send_order);
-            } else {
-                return Err(AppError::InternalServerError(
-                    "expected a incoming order".to_string(),
-                ));
-            }
-        }
-        None => {
-            return Err(AppError::InternalServerError(
-                "expected a user state".to_string(),
-            ))
-        }
-    }
+            let mut engine = GameEngine::new(round_state.clone());
+            engine.end_player_round_with_products(player, msg.placed_order, msg.product_orders)?;
 
-    round_state.players_finished += 1;
+            let round_finished = engine.round_finished();
+            let (new_round_state, events) = engine.finish();
+            *round_state = new_round_state;
 
-    match state.lobbies.write().await.get_mut(&game_id) {
-        Some(lobby_state) => {
-            lobby_state.round_state = round_state.clone();
+            (team, round_finished, events)
         }
         None => {
             return Err(AppError::InternalServerError(
                 "expected a lobby state".to_string(),
             ))
         }
-    }
+    };
+
+    send_team_msgs(&state, game_id, team, events).await?;
 
-    if round_state.players_finished == round_state.players {
-        tracing::debug!("finishing rounds: {}", game_id);
-        finish_round(game_id, &mut round_state, &state, db).await?;
+    if round_finished {
+        tracing::debug!("finishing rounds: {}, team: {}", game_id, team);
+        finish_round(game_id, team, &state, db).await?;
     }
 
     Ok(())
 }
 
-//TODO: FIXME: REFACTOR TO USE MACROS!!
-pub async fn process_game_events(
+pub async fn finish_round(
     game_id: Uuid,
-    round_state: &mut RoundState,
+    team: Uuid,
     state: &Arc<State>,
     db: &PgPool,
 ) -> Result<(), AppError> {
-    let lobby = get_lobby(game_id, db).await?;
-    tracing::debug!("processing events, count: {}", lobby.events.0.events.len());
-    for event in lobby.events.0.events {
-        tracing::debug!("processing event: {}", event.name);
-        let (cond_met, targets) = evaluate_cond(&event, round_state, db, game_id).await?;
-
-        if !cond_met {
-            continue;
-        }
-
-        for action in event.actions {
-            match action {
-                EventAction::ShowMessage { message, target } => {
-                    execute_pop_up_action(target, &targets, state, game_id, message).await?
-                }
-                EventAction::ChangeSettings { new_settings } => {
-                    execute_settings_change(db, round_state, new_settings, state, game_id).await?
-                }
-                EventAction::AddResource {
-                    resource,
-                    target,
-                    value,
-                } => {
-                    execute_resource_action(
-                        target,
-                        &targets,
-                        round_state,
-                        resource,
-                        value,
-                        state,
-                        game_id,
-                    )
-                    .await?
-                }
-            }
-        }
-    }
+    // rounds where only bots are left to play are closed right away
+    while close_round(game_id, team, state, db).await? {}
 
     Ok(())
 }
 
-async fn execute_resource_action(
-    target: ActionTarget,
-    players_targets: &Vec<Uuid>,
-    round_state: &mut RoundState,
-    resource: Resource,
-    value: i64,
-    state: &Arc<State>,
+/// Closes the current round of the team, returns if the next one was already
+/// played by bots.
+async fn close_round(
     game_id: Uuid,
-) -> Result<(), AppError> {
-    Ok(match target {
-        ActionTarget::EventTarget => {
-            for u_id in players_targets {
-                let mut player_state = match round_state.users_states.get_mut(u_id) {
-                    Some(p) => p,
+    team: Uuid,
+    state: &Arc<State>,
+    db: &PgPool,
+) -> Result<bool, AppError> {
+    let lobby = get_lobby(game_id, db).await?;
+
+    let round_state = get_team_state(state, game_id, team).await?;
+    if !GameEngine::new(round_state.clone()).round_finished() {
+        return Ok(false);
+    }
+    let round = round_state.round;
+    let last_state = get_game_state(game_id, team, round, db).await?;
+
+    let mut lobbies = state.lobbies.write().await;
+    let (game_finished, next_round_finished, finished_round, events) =
+        match lobbies.get_mut(&game_id) {
+            Some(lobby_state) => {
+                let round_state = match lobby_state.teams.get_mut(&team) {
+                    Some(r) => r,
                     None => {
                         return Err(AppError::InternalServerError(
-                            "expected user state".to_string(),
+                            "expected a team state".to_string(),
                         ))
                     }
                 };
-                match resource {
-                    Resource::Money => player_state.money += value,
-                    Resource::MagazineState => player_state.magazine_state += value,
-                    Resource::Performance => player_state.performance += value,
-                    Resource::BackOrderValue => player_state.back_order_sum += value,
-                }
-                send_broadcast_msg(
-                    state,
-                    game_id,
-                    EventMessages::GameEventResourceAddedUser(*u_id, resource.clone(), value),
-                )
-                .await?
-            }
-        }
-        ActionTarget::AllPlayers => {
-            for (_, player_state) in &mut round_state.users_states {
-                match resource {
-                    Resource::Money => player_state.money += value,
-                    Resource::MagazineState => player_state.magazine_state += value,
-                    Resource::Performance => player_state.performance += value,
-                    Resource::BackOrderValue => player_state.back_order_sum += value,
-                }
-            }
-            send_broadcast_msg(
-                state,
-                game_id,
-                EventMessages::GameEventResourceAddedAll(resource, value),
-            )
-            .await?
-        }
-    })
-}
-
-async fn execute_settings_change(
-    db: &sqlx::Pool<Postgres>,
-    round_state: &mut RoundState,
-    new_settings: Settings,
-    state: &Arc<State>,
-    game_id: Uuid,
-) -> Result<(), AppError> {
-    sqlx::query!(
-        // language=PostgreSQL
-        r#"update "lobby" set settings = $1 where id = $2"#,
-        sqlx::types::Json(&new_settings) as _,
-        game_id
-    )
-    .execute(db)
-    .await
-    .map_err(|e| AppError::DbErr(e.to_string()))?;
-
-    round_state.settings = new_settings.clone();
-
-    Ok(send_broadcast_msg(
-        state,
-        game_id,
-        EventMessages::GameEventSettingsChange(new_settings),
-    )
-    .await?)
-}
-
-async fn execute_pop_up_action(
-    target: ActionTarget,
-    players_targets: &Vec<Uuid>,
-    state: &Arc<State>,
-    game_id: Uuid,
-    message: String,
-) -> Result<(), AppError> {
-    Ok(match target {
-        ActionTarget::EventTarget => {
-            for player_id in players_targets {
-                send_broadcast_msg(
-                    state,
-                    game_id,
-                    EventMessages::GameEventPopUpUser(*player_id, message.clone()),
-                )
-                .await?
-            }
-        }
-        ActionTarget::AllPlayers => {
-            send_broadcast_msg(
-                state,
-                game_id,
-                EventMessages::GameEventPopUpAll(message.clone()),
-            )
-            .await?
-        }
-    })
-}
 
-async fn evaluate_cond(
-    event: &crate::entities::GameEvent,
-    round_state: &mut RoundState,
-    db: &sqlx::Pool<Postgres>,
-    game_id: Uuid,
-) -> Result<(bool, Vec<Uuid>), AppError> {
-    let (met_by, players_targets) = match event.condition.clone() {
-        EventCondition::RoundMet { round } => evaluate_round_cond(round_state, round),
-        EventCondition::ValueExceed {
-            resource,
-            met_by,
-            value,
-        } => match resource {
-            Resource::Money => evaluate_value_exceed(|us| us.money, met_by, round_state, value),
-            Resource::MagazineState => {
-                evaluate_value_exceed(|us| us.magazine_state, met_by, round_state, value)
-            }
-            Resource::Performance => {
-                evaluate_value_exceed(|us| us.performance, met_by, round_state, value)
-            }
-            Resource::BackOrderValue => {
-                evaluate_value_exceed(|us| us.back_order_sum, met_by, round_state, value)
-            }
-        },
-        EventCondition::SingleChange { resource, value } => {
-            let last_state = sqlx::query_as!(GameState,
-                r#"
-                    select id, round, user_states as "user_states: sqlx::types::Json<BTreeMap<Uuid, UserState>>", round_orders as "round_orders: sqlx::types::Json<BTreeMap<Uuid, Order>>", flow as "flow: sqlx::types::Json<Flow>", demand, supply, send_orders as "send_orders: sqlx::types::Json<BTreeMap<Uuid, Order>>", players_classes as "players_classes: sqlx::types::Json<BTreeMap<Uuid, u32>>", game_id
-                    from "game_state"
-                    where game_id = $1 and round = $2"#,
-                game_id,
-                round_state.round - 1
-            ).fetch_one(db)
-            .await
-            .map_err(|e| {
-                AppError::DbErr(e.to_string())
-            })?;
-
-            match resource {
-                Resource::Money => {
-                    evaluate_single_change(|us| us.money, round_state, last_state, value)
-                }
-                Resource::MagazineState => {
-                    evaluate_single_change(|us| us.magazine_state, round_state, last_state, value)
+                let mut engine = GameEngine::new(round_state.clone());
+                // another task may have closed the round while the last state was fetched
+                if round_state.round != round || !engine.round_finished() {
+                    return Ok(false);
                 }
-                Resource::Performance => {
-                    evaluate_single_change(|us| us.performance, round_state, last_state, value)
-                }
-                Resource::BackOrderValue => {
-                    evaluate_single_change(|us| us.back_order_sum, round_state, last_state, value)
-                }
-            }
-        }
-    };
-    Ok((met_by, players_targets))
-}
+                engine.finish_round(
+                    &lobby.events.0,
+                    last_state.as_ref().map(|s| &s.user_states.0),
+                )?;
+
+                let game_finished = engine.game_finished();
+                let next_round_finished = !game_finished && engine.round_finished();
+                let finished_round = match engine.finished_round() {
+                    Some(r) => r.clone(),
+                    None => {
+                        return Err(AppError::InternalServerError(
+                            "expected a finished round".to_string(),
+                        ))
+                    }
+                };
+                let (new_round_state, events) = engine.finish();
+                *round_state = new_round_state;
 
-fn evaluate_round_cond(round_state: &mut RoundState, round: i64) -> (bool, Vec<Uuid>) {
-    tracing::debug!("assesing round cond, {}, {}", round_state.round, round);
-    let mut players_id = Vec::new();
-    if round_state.round == round {
-        tracing::debug!("round event evaluation");
-        for (u_id, _) in &round_state.users_states {
-            players_id.push(*u_id);
-        }
-    }
-    tracing::debug!("returning: {} {:?}", players_id.len() != 0, players_id);
-    (players_id.len() != 0, players_id)
-}
-
-//TODO: refactor name
-fn evaluate_value_exceed(
-    extractor: fn(&UserState) -> i64,
-    met_by: MetBy,
-    round_state: &mut RoundState,
-    value: i64,
-) -> (bool, Vec<Uuid>) {
-    let mut recipients = Vec::new();
-    let met = match met_by {
-        MetBy::SinglePlayer => {
-            for (u_id, user_state) in &round_state.users_states {
-                if extractor(user_state) > value {
-                    recipients.push(*u_id)
-                }
+                (game_finished, next_round_finished, finished_round, events)
             }
-            recipients.len() != 0
-        }
-        MetBy::Average => {
-            let mut sum = 0;
-            for (u_id, user_state) in &round_state.users_states {
-                sum += extractor(user_state);
-                recipients.push(*u_id);
-            }
-            (sum / round_state.users_states.len() as i64) > value
-        }
-        MetBy::AllPlayers => {
-            let mut val_met = true;
-            for (u_id, user_state) in &round_state.users_states {
-                if extractor(user_state) < value {
-                    val_met = false;
-                    break;
-                } else {
-                    recipients.push(*u_id);
-                }
+            None => {
+                return Err(AppError::InternalServerError(
+                    "expected a lobby state".to_string(),
+                ))
             }
-            val_met
-        }
-    };
-    (met, recipients)
-}
+        };
 
-fn evaluate_single_change(
-    extractor: fn(&UserState) -> i64,
-    round_state: &mut RoundState,
-    last_state: GameState,
-    value: i64,
-) -> (bool, Vec<Uuid>) {
-    let mut recipients = Vec::new();
+    // saved before the lobby is unlocked, so a rewind can't miss the new round
+    save_game_state(game_id, &finished_round, db).await?;
+    drop(lobbies);
 
-    for (u_id, user_state) in &round_state.users_states {
-        let last_user_state = match last_state.user_states.get(u_id) {
-            Some(s) => s,
-            None => continue, //user disconnected probably
-        };
+    schedule_round_deadline(game_id, team, &events, state, db);
+    send_team_msgs(state, game_id, team, events).await?;
 
-        if (extractor(user_state) - extractor(last_user_state)).abs() > value {
-            recipients.push(*u_id)
-        }
+    if game_finished {
+        finish_game(game_id, team, state, db).await?;
     }
 
-    (recipients.len() != 0, recipients)
+    Ok(next_round_finished)
 }
 
-pub async fn finish_round(
+/// Sends the results to a team that played its last round.
+pub async fn finish_game(
     game_id: Uuid,
-    round_state: &mut RoundState,
+    team: Uuid,
     state: &Arc<State>,
     db: &PgPool,
 ) -> Result<(), AppError> {
-    send_broadcast_msg(state, game_id, EventMessages::RoundEnd).await?;
-
-    tracing::debug!("finishing round, generating demand");
-    let next_demand = generate_demand(round_state.demand, &round_state.settings.demand_style);
-    let next_demand_cost = round_state.settings.resource_basic_price * next_demand;
-    let generated_order = Order {
-        recipient: Uuid::nil(),
-        sender: round_state.flow.last_player,
-        value: next_demand,
-        cost: next_demand_cost,
-    };
+    let round_state = get_team_state(state, game_id, team).await?;
 
-    round_state
-        .round_orders
-        .insert(Uuid::nil(), generated_order);
+    let stats_types = vec![
+        UserStatsType::Money,
+        UserStatsType::MagazineState,
+        UserStatsType::BackOrder,
+        UserStatsType::PlacedOrder,
+        UserStatsType::ReceivedOrder,
+        UserStatsType::SpentMoney,
+        UserStatsType::Performance,
+    ];
 
-    tracing::debug!("round orders {:?}", round_state.round_orders);
+    let stats = get_player_stats(game_id, db, stats_types.clone()).await?;
+    let teams_stats = get_teams_stats(game_id, db, stats_types).await?;
+    let msg = GameEnd {
+        player_states: round_state.users_states,
+        stats,
+        teams_stats,
+    };
 
-    let mut generated_order_supply =
-        match round_state.round_orders.get(&round_state.flow.first_player) {
-            Some(o) => o.clone(),
-            None => {
-                return Err(AppError::InternalServerError(
-                    "not found fist player order".to_string(),
-                ))
-            }
-        };
+    send_team_msgs(state, game_id, team, vec![EventMessages::GameEnd(msg)]).await?;
+    Ok(())
+}
 
-    let next_supply = generate_demand(round_state.supply, &round_state.settings.supply_style);
-    if next_supply < generated_order_supply.value {
-        let next_supply_cost = round_state.settings.resource_basic_price * next_supply;
-        generated_order_supply = Order {
-            recipient: round_state.flow.first_player,
-            sender: Uuid::nil(),
-            value: next_supply,
-            cost: next_supply_cost,
-        };
-    }
+/// Starts the chains of every team together and saves their first rounds. The
+/// teams are only put in play by `play_new_game`, once the transaction committed.
+pub async fn start_new_game(
+    tx: &mut Transaction<'_, Postgres>,
+    id: Uuid,
+    lobby: Lobby,
+    players: Vec<User>,
+    start: StartGame,
+) -> Result<Vec<GameEngine>, AppError> {
+    tracing::debug!(
+        "initing game, players_count, {} players; {:?}",
+        players.len(),
+        players
+    );
 
-    round_state
-        .send_orders
-        .insert(Uuid::nil(), generated_order_supply);
+    lobby.settings.0.validate()?;
+    lobby.events.0.validate(&lobby.settings.0)?;
 
-    for (_, order) in &round_state.round_orders {
-        if order.sender.is_nil() {
-            continue;
-        }
+    let players_ids: Vec<Uuid> = players.iter().map(|p| p.id).collect();
+    let engines = GameEngine::start_teams(
+        &lobby.settings.0,
+        players_ids,
+        start,
+        &mut rand::thread_rng(),
+    )?;
 
-        tracing::debug!(
-            "trying to push order for sender {}, recipient: {}",
-            order.sender,
-            order.recipient
-        );
-        match round_state.users_states.get_mut(&order.sender) {
-            Some(us) => us.requested_orders.push(order.clone()),
+    for engine in &engines {
+        let round_state = match engine.finished_round() {
+            Some(r) => r,
             None => {
-                return Err(AppError::InternalServerError(format!(
-                    "not found placed recipient for order {:?}",
-                    order
-                )))
+                return Err(AppError::InternalServerError(
+                    "expected a started round".to_string(),
+                ))
             }
-        }
+        };
+        save_game_state(id, round_state, &mut *tx).await?;
     }
 
-    for (_, order) in &round_state.send_orders {
-        if order.recipient.is_nil() {
-            continue;
-        }
+    Ok(engines)
+}
 
-        tracing::debug!("trying to push order for recipient {}", order.recipient);
-        match round_state.users_states.get_mut(&order.recipient) {
-            Some(us) => us.incoming_orders.push(order.clone()),
-            None => {
-                return Err(AppError::InternalServerError(format!(
-                    "not found sent recipient for order {:?}",
-                    order
-                )))
-            }
-        }
+/// Puts the teams started by `start_new_game` in play and closes the rounds
+/// already played by bots.
+pub async fn play_new_game(
+    id: Uuid,
+    engines: Vec<GameEngine>,
+    state: &Arc<State>,
+    db: &PgPool,
+) -> Result<(), AppError> {
+    let mut teams = BTreeMap::new();
+    let mut teams_events = Vec::new();
+    let mut finished_teams = Vec::new();
+    for engine in engines {
+        let round_finished = engine.round_finished();
+        let (round_state, events) = engine.finish();
+        if round_finished {
+            finished_teams.push(round_state.team.id);
+        }
+
+        teams_events.push((round_state.team.id, events));
+        teams.insert(round_state.team.id, round_state);
     }
 
-    round_state.round += 1;
-    round_state.demand = next_demand;
-
-    match state.lobbies.write().await.get_mut(&game_id) {
+    match state.lobbies.write().await.get_mut(&id) {
         Some(lobby_state) => {
-            lobby_state.round_state = round_state.clone();
+            lobby_state.started = true;
+            lobby_state.teams = teams;
         }
         None => {
             return Err(AppError::InternalServerError(
@@ -611,285 +511,224 @@ pub async fn finish_round(
         }
     }
 
-    sqlx::query_as!(GameState,
-        // language=PostgreSQL
-        r#"insert into "game_state" 
-        (round, user_states, round_orders, send_orders, players_classes, flow, demand, supply, game_id) 
-        values ($1, $2, $3, $4, $5, $6, $7, $8, $9) 
-        returning id, round, user_states as "user_states: sqlx::types::Json<BTreeMap<Uuid, UserState>>", round_orders as "round_orders: sqlx::types::Json<BTreeMap<Uuid, Order>>", flow as "flow: sqlx::types::Json<Flow>", demand, supply, send_orders as "send_orders: sqlx::types::Json<BTreeMap<Uuid, Order>>", players_classes as "players_classes: sqlx::types::Json<BTreeMap<Uuid, u32>>", game_id "#,
-        round_state.round,
-        sqlx::types::Json(&round_state.users_states) as _,
-        sqlx::types::Json(&round_state.round_orders) as _,
-        sqlx::types::Json(&round_state.send_orders) as _,
-        sqlx::types::Json(&round_state.player_classes) as _,
-        sqlx::types::Json(&round_state.flow) as _,
-        round_state.demand,
-        round_state.supply,
-        game_id
-    )
-    .fetch_one(db)
-    .await
-    .map_err(|e| {
-        AppError::DbErr(e.to_string())
-    })?;
+    for (team, events) in teams_events {
+        schedule_round_deadline(id, team, &events, state, db);
+        send_team_msgs(state, id, team, events).await?;
+    }
 
-    let lobby = get_lobby(game_id, db).await?;
-    if round_state.round == lobby.settings.max_rounds {
-        finish_game(game_id, round_state, state, db).await?;
-    } else {
-        new_round(game_id, round_state, state, db).await?;
+    for team in finished_teams {
+        finish_round(id, team, state, db).await?;
     }
 
     Ok(())
 }
 
-pub async fn new_round(
+/// Lets a bot play the position of `player`, or gives it back to the player.
+pub async fn set_player_bot(
     game_id: Uuid,
-    round_state: &mut RoundState,
+    player: Uuid,
+    policy: Option<BotPolicy>,
     state: &Arc<State>,
     db: &PgPool,
 ) -> Result<(), AppError> {
-    process_game_events(game_id, round_state, state, db).await?;
+    let (team, round_finished, events) = match state.lobbies.write().await.get_mut(&game_id) {
+        Some(lobby_state) => {
+            if lobby_state.paused {
+                return Err(AppError::GamePaused(game_id.to_string()));
+            }
 
-    let send_orders = round_state.send_orders.clone();
-    let round_orders = round_state.round_orders.clone();
+            let team = match lobby_state.team_of(&player) {
+                Some(t) => t,
+                None => {
+                    return Err(AppError::BadRequest(
+                        "player is not in any team".to_string(),
+                    ))
+                }
+            };
+            let round_state = match lobby_state.teams.get_mut(&team) {
+                Some(r) => r,
+                None => {
+                    return Err(AppError::InternalServerError(
+                        "expected a team state".to_string(),
+                    ))
+                }
+            };
 
-    round_state.players_finished = 0;
-    round_state.round_orders.clear();
-    round_state.send_orders.clear();
+            let mut engine = GameEngine::new(round_state.clone());
+            let round_was_finished = engine.round_finished();
+            engine.set_bot(player, policy)?;
 
-    match state.lobbies.write().await.get_mut(&game_id) {
-        Some(lobby_state) => {
-            lobby_state.round_state = round_state.clone();
+            let round_finished = !round_was_finished && engine.round_finished();
+            let (new_round_state, events) = engine.finish();
+            *round_state = new_round_state;
+
+            (team, round_finished, events)
         }
         None => {
             return Err(AppError::InternalServerError(
                 "expected a lobby state".to_string(),
             ))
         }
-    }
-
-    let msg = GameUpdate {
-        player_states: round_state.users_states.clone(),
-        round: round_state.round,
-        flow: round_state.flow.clone(),
-        settings: round_state.settings.clone(),
-        round_orders: round_orders,
-        send_orders: send_orders,
-        player_classes: round_state.player_classes.clone(),
     };
 
-    send_broadcast_msg(state, game_id, EventMessages::RoundStart(msg)).await?;
+    send_team_msgs(state, game_id, team, events).await?;
+
+    if round_finished {
+        finish_round(game_id, team, state, db).await?;
+    }
 
     Ok(())
 }
 
-pub async fn finish_game(
+/// Starts the timer of a round when the engine announced a deadline for it.
+fn schedule_round_deadline(
     game_id: Uuid,
-    round_state: &mut RoundState,
+    team: Uuid,
+    events: &[EventMessages],
     state: &Arc<State>,
     db: &PgPool,
-) -> Result<(), AppError> {
-    let stats_types = vec![
-        UserStatsType::Money,
-        UserStatsType::MagazineState,
-        UserStatsType::BackOrder,
-        UserStatsType::PlacedOrder,
-        UserStatsType::ReceivedOrder,
-        UserStatsType::SpentMoney,
-    ];
-
-    let stats = get_player_stats(game_id, db, stats_types).await?;
-    let msg = GameEnd {
-        player_states: round_state.users_states.clone(),
-        stats: stats,
-    };
+) {
+    for event in events {
+        if let EventMessages::RoundDeadline(round, seconds) = event {
+            let (round, seconds) = (*round, *seconds);
+            let state = state.clone();
+            let db = db.clone();
+
+            tokio::spawn(async move {
+                let generation = match state.lobbies.write().await.get_mut(&game_id) {
+                    Some(lobby_state) if lobby_state.paused => {
+                        lobby_state.timers.frozen.insert(team, (round, seconds));
+                        return;
+                    }
+                    Some(lobby_state) => {
+                        let ends = Instant::now() + Duration::from_secs(seconds);
+                        lobby_state.timers.running.insert(team, (round, ends));
+                        lobby_state.timers.generation
+                    }
+                    None => return,
+                };
 
-    send_broadcast_msg(state, game_id, EventMessages::GameEnd(msg)).await?;
-    Ok(())
+                tokio::time::sleep(Duration::from_secs(seconds)).await;
+                if let Err(e) = round_deadline(game_id, team, round, generation, state, db).await {
+                    tracing::error!("error while closing round on deadline {}", e);
+                }
+            });
+        }
+    }
 }
 
-pub async fn start_new_game(
-    tx: &mut Transaction<'_, Postgres>,
-    id: Uuid,
-    lobby: Lobby,
-    players: Vec<User>,
-    players_classes: BTreeMap<Uuid, u32>,
-    state: &Arc<State>,
+/// Places the default orders of players that didn't finish `round` in time and
+/// closes the round.
+async fn round_deadline(
+    game_id: Uuid,
+    team: Uuid,
+    round: i64,
+    generation: u64,
+    state: Arc<State>,
+    db: PgPool,
 ) -> Result<(), AppError> {
-    let init_orders: BTreeMap<Uuid, Order> = BTreeMap::new();
-    let mut init_players_states: BTreeMap<Uuid, UserState> = BTreeMap::new();
-    let players_count = players.len() as i64;
-    let init_send_order: BTreeMap<Uuid, Order> = BTreeMap::new();
-    let flow = redistribute_flow(&players)?;
-
-    for player in &players {
-        let player_class;
-        match players_classes.get(&player.id) {
-            Some(c) => player_class = c,
-            None => return Err(AppError::BadRequest("Player not found".to_string())),
-        };
+    let events = match state.lobbies.write().await.get_mut(&game_id) {
+        // the timer was frozen by a pause
+        Some(lobby_state) if lobby_state.paused || lobby_state.timers.generation != generation => {
+            return Ok(())
+        }
+        Some(lobby_state) => match lobby_state.teams.get_mut(&team) {
+            Some(round_state) => {
+                let mut engine = GameEngine::new(round_state.clone());
+                // the round was already closed by the players
+                if round_state.round != round || engine.round_finished() {
+                    return Ok(());
+                }
 
-        let start_money;
-        match lobby.settings.start_money.get(&player_class) {
-            Some(c) => start_money = c,
-            None => {
-                return Err(AppError::BadRequest(
-                    "Player not found start money".to_string(),
-                ))
-            }
-        }
+                engine.submit_missing_orders()?;
+                let (new_round_state, events) = engine.finish();
+                *round_state = new_round_state;
 
-        let start_magazine;
-        match lobby.settings.start_magazine.get(&player_class) {
-            Some(c) => start_magazine = c,
-            None => {
-                return Err(AppError::BadRequest(
-                    "Player not found start magazine".to_string(),
-                ))
+                events
             }
-        }
-
-        let incoming_orders_values;
-        match lobby.settings.incoming_start_queue.get(&player_class) {
-            Some(c) => incoming_orders_values = c.clone(),
-            None => return Err(AppError::BadRequest("Player not found".to_string())),
-        }
+            None => return Ok(()),
+        },
+        None => return Ok(()),
+    };
 
-        let sender_id = flow.get_sender(&player.id)?;
+    send_team_msgs(&state, game_id, team, events).await?;
+    finish_round(game_id, team, &state, &db).await
+}
 
-        let mut incoming_orders: Vec<Order> = Vec::new();
-        for incoming_order in incoming_orders_values {
-            incoming_orders.push(Order {
-                recipient: player.id,
-                sender: sender_id,
-                value: incoming_order,
-                cost: lobby.settings.resource_basic_price * incoming_order,
-            })
+/// Stops accepting orders and freezes the round timers of every team.
+pub async fn pause_game(game_id: Uuid, state: &Arc<State>) -> Result<(), AppError> {
+    match state.lobbies.write().await.get_mut(&game_id) {
+        Some(lobby_state) => {
+            lobby_state.paused = true;
+            lobby_state.timers.freeze();
         }
-
-        let requested_orders_values;
-        match lobby.settings.requested_start_queue.get(&player_class) {
-            Some(c) => requested_orders_values = c.clone(),
-            None => return Err(AppError::BadRequest("Player not found".to_string())),
+        None => {
+            return Err(AppError::InternalServerError(
+                "expected a lobby state".to_string(),
+            ))
         }
+    }
 
-        let recipient = match flow.flow.get(&player.id) {
-            Some(p) => p,
-            None => {
-                return Err(AppError::BadRequest(
-                    "bad flow, no player recipient found".to_string(),
-                ))
-            }
-        };
+    send_broadcast_msg(state, game_id, EventMessages::GamePaused).await
+}
 
-        let mut requested_orders: Vec<Order> = Vec::new();
-        for requested_order in requested_orders_values {
-            requested_orders.push(Order {
-                recipient: *recipient,
-                sender: player.id,
-                value: requested_order,
-                cost: lobby.settings.resource_basic_price * requested_order,
-            })
+/// Accepts orders again and restarts the frozen timers with the time they had left.
+pub async fn resume_game(game_id: Uuid, state: &Arc<State>, db: &PgPool) -> Result<(), AppError> {
+    let timers: Vec<(Uuid, i64, u64)> = match state.lobbies.write().await.get_mut(&game_id) {
+        Some(lobby_state) => {
+            lobby_state.paused = false;
+            let frozen = std::mem::take(&mut lobby_state.timers.frozen);
+            let teams = &lobby_state.teams;
+            frozen
+                .into_iter()
+                .filter(|(team, (round, _))| teams.get(team).map(|r| r.round) == Some(*round))
+                .map(|(team, (round, seconds))| (team, round, seconds))
+                .collect()
         }
+        None => {
+            return Err(AppError::InternalServerError(
+                "expected a lobby state".to_string(),
+            ))
+        }
+    };
 
-        let user_state = UserState {
-            user_id: player.id,
-            money: *start_money,
-            spent_money: 0,
-            magazine_state: *start_magazine,
-            performance: 0, //TODO, fill with performance
-            incoming_orders: incoming_orders,
-            requested_orders: requested_orders,
-            back_order_sum: 0,
-            placed_order: Order::default(),
-            received_order: Order::default(),
-            sent_orders: Vec::new(),
-        };
-
-        init_players_states.insert(player.id, user_state);
+    send_broadcast_msg(state, game_id, EventMessages::GameResumed).await?;
+    for (team, round, seconds) in timers {
+        let events = vec![EventMessages::RoundDeadline(round, seconds)];
+        schedule_round_deadline(game_id, team, &events, state, db);
+        send_team_msgs(state, game_id, team, events).await?;
     }
 
-    let demand = match &lobby.settings.demand_style {
-        crate::entities::GeneratedOrderStyle::Default => 10,
-        crate::entities::GeneratedOrderStyle::Linear { start, increase: _ } => *start,
-        crate::entities::GeneratedOrderStyle::Multiplication { start, increase: _ } => *start,
-        crate::entities::GeneratedOrderStyle::Exponential {
-            start,
-            power: _,
-            modulator: _,
-        } => *start,
-        crate::entities::GeneratedOrderStyle::List { list: demand } => match demand.first() {
-            Some(d) => *d,
-            None => return Err(AppError::BadRequest("bad list demand".to_string())),
-        },
-    };
+    Ok(())
+}
 
-    let supply = match &lobby.settings.supply_style {
-        crate::entities::GeneratedOrderStyle::Default => 10,
-        crate::entities::GeneratedOrderStyle::Linear { start, increase: _ } => *start,
-        crate::entities::GeneratedOrderStyle::Multiplication { start, increase: _ } => *start,
-        crate::entities::GeneratedOrderStyle::Exponential {
-            start,
-            power: _,
-            modulator: _,
-        } => *start,
-        crate::entities::GeneratedOrderStyle::List { list: demand } => match demand.first() {
-            Some(d) => *d,
-            None => return Err(AppError::BadRequest("bad list demand".to_string())),
-        },
+/// Ends the game of every team before its last round, players get the results
+/// of the rounds played so far.
+pub async fn abort_game(game_id: Uuid, state: &Arc<State>, db: &PgPool) -> Result<(), AppError> {
+    let teams: Vec<Uuid> = match state.lobbies.write().await.get_mut(&game_id) {
+        Some(lobby_state) => {
+            // nothing is played while the results are gathered
+            lobby_state.paused = true;
+            lobby_state.timers.freeze();
+            lobby_state.teams.keys().copied().collect()
+        }
+        None => {
+            return Err(AppError::InternalServerError(
+                "expected a lobby state".to_string(),
+            ))
+        }
     };
 
-    sqlx::query_as!(GameState,
-        // language=PostgreSQL
-        r#"insert into "game_state" 
-        (round, user_states, round_orders, send_orders, flow, players_classes, demand, supply, game_id) 
-        values ($1, $2, $3, $4, $5, $6, $7, $8, $9) 
-        returning id, round, user_states as "user_states: sqlx::types::Json<BTreeMap<Uuid, UserState>>", round_orders as "round_orders: sqlx::types::Json<BTreeMap<Uuid, Order>>", flow as "flow: sqlx::types::Json<Flow>", demand, supply, send_orders as "send_orders: sqlx::types::Json<BTreeMap<Uuid, Order>>", players_classes as "players_classes: sqlx::types::Json<BTreeMap<Uuid, u32>>",game_id "#,
-        0,
-        sqlx::types::Json(&init_players_states) as _,
-        sqlx::types::Json(init_orders) as _,
-        sqlx::types::Json(init_send_order) as _,
-        sqlx::types::Json(&flow) as _,
-        sqlx::types::Json(&players_classes) as _,
-        demand,
-        supply,
-        id
-    )
-    .fetch_one(&mut *tx)
-    .await
-    .map_err(|e| {
-        AppError::DbErr(e.to_string())
-    })?;
-
-    let msg;
-
-    tracing::debug!(
-        "initing game, players_count, {} players; {:?}",
-        players_count,
-        players
-    );
+    for team in teams {
+        finish_game(game_id, team, state, db).await?;
+    }
 
-    match state.lobbies.write().await.get_mut(&id) {
+    match state.lobbies.write().await.get_mut(&game_id) {
         Some(lobby_state) => {
-            lobby_state.started = true;
-            lobby_state.round_state.flow = flow.clone();
-            lobby_state.round_state.round = 0;
-            lobby_state.round_state.players = players_count;
-            lobby_state.round_state.players_finished = 0;
-            lobby_state.round_state.users_states = init_players_states.clone();
-            lobby_state.round_state.settings = lobby.settings.0.clone();
-            lobby_state.round_state.player_classes = players_classes;
-
-            msg = GameUpdate {
-                player_states: init_players_states.clone(),
-                round: 0,
-                flow: flow.clone(),
-                settings: lobby.settings.0.clone(),
-                round_orders: lobby_state.round_state.round_orders.clone(),
-                send_orders: lobby_state.round_state.send_orders.clone(),
-                player_classes: lobby_state.round_state.player_classes.clone(),
-            };
+            lobby_state.started = false;
+            lobby_state.paused = false;
+            lobby_state.teams.clear();
+            lobby_state.timers.frozen.clear();
         }
         None => {
             return Err(AppError::InternalServerError(
@@ -898,77 +737,216 @@ pub async fn start_new_game(
         }
     }
 
-    send_broadcast_msg(state, id, EventMessages::GameStart(msg)).await?;
-    Ok(())
+    send_broadcast_msg(state, game_id, EventMessages::GameAborted).await
 }
 
-//TODO: make sure owner is not in players
-//TODO: enforce min players number?
-fn redistribute_flow(players: &Vec<User>) -> Result<Flow, AppError> {
-    let last_player = match players.last() {
-        Some(p) => p,
-        None => {
-            return Err(AppError::InternalServerError(
-                "expected last player for flow redistribute".to_string(),
-            ))
-        }
-    };
+/// Rolls every team back to the start of `round` from the saved snapshots. The
+/// snapshots of later rounds are deleted.
+pub async fn rewind_game(
+    game_id: Uuid,
+    round: i64,
+    state: &Arc<State>,
+    db: &PgPool,
+) -> Result<(), AppError> {
+    let lobby = get_lobby(game_id, db).await?;
 
-    let first_player = match players.first() {
-        Some(p) => p,
+    // the lobby stays locked until the rewound rounds replace the current ones, no
+    // round can close in between
+    let mut lobbies = state.lobbies.write().await;
+    let lobby_state = match lobbies.get_mut(&game_id) {
+        Some(lobby_state) => lobby_state,
         None => {
             return Err(AppError::InternalServerError(
-                "expected first player for flow redistribute".to_string(),
+                "expected a lobby state".to_string(),
             ))
         }
     };
 
-    let mut flow_map = BTreeMap::new();
+    if lobby_state.teams.values().any(|r| r.round < round) || round < 0 {
+        return Err(AppError::BadRequest(format!(
+            "round {} wasn't played yet",
+            round
+        )));
+    }
+
+    let mut tx = db
+        .begin()
+        .await
+        .map_err(|e| AppError::DbErr(e.to_string()))?;
 
-    for i in 0..players.len() {
-        let cur_player = players[i].id;
-        let next_player = match players.get(i + 1) {
-            Some(p) => p.id,
-            None => Uuid::nil(),
+    let mut rewound = Vec::new();
+    for (team, round_state) in &lobby_state.teams {
+        let snapshot = match get_game_state(game_id, *team, round, &mut tx).await? {
+            Some(s) => s,
+            None => {
+                return Err(AppError::NotFound(format!(
+                    "no snapshot of round {} for team {}",
+                    round, round_state.team.name
+                )))
+            }
         };
+        let last_state = get_game_state(game_id, *team, round - 1, &mut tx).await?;
+        let mut round_state = RoundState::from_snapshot(snapshot, lobby.settings.0.clone());
+        // the snapshot has the settings its round's events left
+        if let Some(settings) = last_state.as_ref().and_then(|s| s.settings.as_ref()) {
+            round_state.settings = settings.0.clone();
+        }
+        let mut engine = GameEngine::new(round_state);
+        engine.rewind(
+            &lobby.events.0,
+            last_state.as_ref().map(|s| &s.user_states.0),
+        )?;
+        rewound.push(engine);
+    }
+
+    sqlx::query!(
+        // language=PostgreSQL
+        r#"delete from "game_state" where game_id = $1 and round > $2"#,
+        game_id,
+        round
+    )
+    .execute(&mut tx)
+    .await
+    .map_err(|e| AppError::DbErr(e.to_string()))?;
+
+    tx.commit()
+        .await
+        .map_err(|e| AppError::DbErr(e.to_string()))?;
+
+    let mut teams_events = Vec::new();
+    let mut finished_teams = Vec::new();
+    lobby_state.timers.reset();
+    for engine in rewound {
+        let round_finished = engine.round_finished();
+        let (round_state, events) = engine.finish();
+        if round_finished {
+            finished_teams.push(round_state.team.id);
+        }
+        teams_events.push((round_state.team.id, events));
+        lobby_state.teams.insert(round_state.team.id, round_state);
+    }
+    drop(lobbies);
 
-        flow_map.insert(cur_player, next_player);
+    for (team, events) in teams_events {
+        schedule_round_deadline(game_id, team, &events, state, db);
+        send_team_msgs(state, game_id, team, events).await?;
     }
 
-    print!("players {:?}", players);
-    print!("flow {:?}", flow_map);
+    for team in finished_teams {
+        finish_round(game_id, team, state, db).await?;
+    }
 
-    Ok(Flow {
-        last_player: last_player.id,
-        first_player: first_player.id,
-        flow: flow_map,
-    })
+    Ok(())
 }
 
-fn generate_demand(last_demand: i64, demand_style: &GeneratedOrderStyle) -> i64 {
-    match &demand_style {
-        crate::entities::GeneratedOrderStyle::Default => (last_demand as f64 * 1.5) as i64,
-        crate::entities::GeneratedOrderStyle::Linear { start: _, increase } => {
-            last_demand + increase
-        }
-        crate::entities::GeneratedOrderStyle::Multiplication { start: _, increase } => {
-            last_demand * increase
-        }
-        crate::entities::GeneratedOrderStyle::Exponential {
-            start: _,
-            power,
-            modulator,
-        } => last_demand * (modulator * (E.powi(*power as i32)) as i64),
-        crate::entities::GeneratedOrderStyle::List { list: demand } => {
-            let index = match demand.iter().position(|&r| r == last_demand) {
-                Some(i) => i,
-                None => demand.len() - 1,
-            };
+async fn get_team_state(
+    state: &Arc<State>,
+    game_id: Uuid,
+    team: Uuid,
+) -> Result<RoundState, AppError> {
+    match state.lobbies.read().await.get(&game_id) {
+        Some(lobby_state) => match lobby_state.teams.get(&team) {
+            Some(round_state) => Ok(round_state.clone()),
+            None => Err(AppError::InternalServerError(
+                "expected a team state".to_string(),
+            )),
+        },
+        None => Err(AppError::InternalServerError(
+            "expected a lobby state".to_string(),
+        )),
+    }
+}
 
-            match demand.get(index) {
-                Some(d) => *d,
-                None => last_demand,
-            }
-        }
+/// Messages of a team chain are only delivered to its players and to observers
+/// outside of all teams.
+async fn send_team_msgs(
+    state: &Arc<State>,
+    game_id: Uuid,
+    team: Uuid,
+    msgs: Vec<EventMessages>,
+) -> Result<(), AppError> {
+    let msgs = msgs
+        .into_iter()
+        .map(|msg| EventMessages::Team(team, Box::new(msg)))
+        .collect();
+
+    send_broadcast_msgs(state, game_id, msgs).await
+}
+
+async fn send_broadcast_msgs(
+    state: &Arc<State>,
+    game_id: Uuid,
+    msgs: Vec<EventMessages>,
+) -> Result<(), AppError> {
+    for msg in msgs {
+        send_broadcast_msg(state, game_id, msg).await?;
     }
+
+    Ok(())
+}
+
+pub async fn get_game_state<'a, E>(
+    game_id: Uuid,
+    team: Uuid,
+    round: i64,
+    db: E,
+) -> Result<Option<GameState>, AppError>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    sqlx::query_as!(GameState,
+        r#"
+            select id, round, user_states as "user_states: sqlx::types::Json<BTreeMap<Uuid, UserState>>", round_orders as "round_orders: sqlx::types::Json<BTreeMap<Uuid, Order>>", flow as "flow: sqlx::types::Json<Flow>", demand, supply, send_orders as "send_orders: sqlx::types::Json<BTreeMap<Uuid, Order>>", players_classes as "players_classes: sqlx::types::Json<BTreeMap<Uuid, u32>>", game_id, team_id, team_name, seed, supply_ordered, supply_delivered, products as "products: sqlx::types::Json<BTreeMap<String, ProductRound>>", fired_events as "fired_events: sqlx::types::Json<BTreeMap<usize, Vec<i64>>>", disruptions as "disruptions: sqlx::types::Json<Vec<Disruption>>", delayed_shipments as "delayed_shipments: sqlx::types::Json<Vec<DelayedShipment>>", settings as "settings: sqlx::types::Json<Settings>"
+            from "game_state"
+            where game_id = $1 and team_id = $2 and round = $3"#,
+        game_id,
+        team,
+        round
+    ).fetch_optional(db)
+    .await
+    .map_err(|e| {
+        AppError::DbErr(e.to_string())
+    })
+}
+
+async fn save_game_state<'a, E>(
+    game_id: Uuid,
+    round_state: &RoundState,
+    db: E,
+) -> Result<(), AppError>
+where
+    E: Executor<'a, Database = Postgres>,
+{
+    sqlx::query!(
+        // language=PostgreSQL
+        r#"insert into "game_state" 
+        (round, user_states, round_orders, send_orders, players_classes, flow, demand, supply, game_id, team_id, team_name, seed, supply_ordered, supply_delivered, products, fired_events, disruptions, delayed_shipments, settings) 
+        values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $19)"#,
+        round_state.round,
+        sqlx::types::Json(&round_state.users_states) as _,
+        sqlx::types::Json(&round_state.round_orders) as _,
+        sqlx::types::Json(&round_state.send_orders) as _,
+        sqlx::types::Json(&round_state.player_classes) as _,
+        sqlx::types::Json(&round_state.flow) as _,
+        round_state.demand,
+        round_state.supply.capacity,
+        game_id,
+        round_state.team.id,
+        round_state.team.name,
+        round_state.seed as i64,
+        round_state.supply.ordered,
+        round_state.supply.delivered,
+        sqlx::types::Json(&round_state.products) as _,
+        sqlx::types::Json(&round_state.fired_events) as _,
+        sqlx::types::Json(&round_state.disruptions) as _,
+        sqlx::types::Json(&round_state.delayed_shipments) as _,
+        sqlx::types::Json(&round_state.settings) as _
+    )
+    .execute(db)
+    .await
+    .map_err(|e| {
+        AppError::DbErr(e.to_string())
+    })?;
+
+    Ok(())
 }
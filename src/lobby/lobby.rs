@@ -84,7 +84,10 @@ pub async fn create_lobby(
     }
 
     let settings = match payload.settings {
-        Some(s) => s,
+        Some(s) => {
+            s.validate()?;
+            s
+        }
         None => Settings::default(),
     };
 
@@ -92,10 +95,11 @@ pub async fn create_lobby(
         Some(ge) => ge,
         None => GameEvents::new(),
     };
+    events.validate(&settings)?;
 
     let lobby = sqlx::query_as!(Lobby,
         // language=PostgreSQL
-        r#"insert into "lobby" (name, password, public, connect_code, code_use_times, max_players, owner_id, started, settings, events) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) returning id, name, password, public, connect_code, code_use_times, max_players, started, owner_id, settings as "settings: sqlx::types::Json<Settings>", events as "events: sqlx::types::Json<GameEvents>""#,
+        r#"insert into "lobby" (name, password, public, connect_code, code_use_times, max_players, owner_id, started, settings, events) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) returning id, name, password, public, connect_code, code_use_times, max_players, started, paused, owner_id, settings as "settings: sqlx::types::Json<Settings>", events as "events: sqlx::types::Json<GameEvents>""#,
         payload.name,
         payload.password,
         payload.public,
@@ -118,15 +122,11 @@ pub async fn create_lobby(
     }
 
     let (tx, rx) = sync::broadcast::channel(MAX_PLAYERS);
-    state.lobbies.write().await.insert(
-        lobby.id,
-        LobbyState {
-            sender: Arc::new(tx),
-            _receiver: Arc::new(rx),
-            started: false,
-            round_state: crate::RoundState::new(),
-        },
-    );
+    state
+        .lobbies
+        .write()
+        .await
+        .insert(lobby.id, LobbyState::new(tx, rx));
 
     Ok(LobbyResponse {
         lobby,
@@ -159,7 +159,7 @@ where
 {
     let lobby = sqlx::query_as!(Lobby,
         // language=PostgreSQL
-        r#"select id, name, password, public, connect_code, code_use_times, max_players, started, owner_id, settings as "settings: sqlx::types::Json<Settings>", events as "events: sqlx::types::Json<GameEvents>" from "lobby" where id = $1"#,
+        r#"select id, name, password, public, connect_code, code_use_times, max_players, started, paused, owner_id, settings as "settings: sqlx::types::Json<Settings>", events as "events: sqlx::types::Json<GameEvents>" from "lobby" where id = $1"#,
         id
     )
     .fetch_one(db)
@@ -177,7 +177,7 @@ pub async fn get_lobby_transaction(
 ) -> Result<Lobby, AppError> {
     let lobby = sqlx::query_as!(Lobby,
         // language=PostgreSQL
-        r#"select id, name, password, public, connect_code, code_use_times, max_players, started, owner_id, settings as "settings: sqlx::types::Json<Settings>", events as "events: sqlx::types::Json<GameEvents>" from "lobby" where id = $1"#,
+        r#"select id, name, password, public, connect_code, code_use_times, max_players, started, paused, owner_id, settings as "settings: sqlx::types::Json<Settings>", events as "events: sqlx::types::Json<GameEvents>" from "lobby" where id = $1"#,
         id
     )
     .fetch_one(tx)
@@ -304,7 +304,10 @@ pub async fn update_lobby(
     }
 
     let settings = match payload.settings {
-        Some(s) => s,
+        Some(s) => {
+            s.validate()?;
+            s
+        }
         None => old.lobby.settings.0,
     };
 
@@ -312,10 +315,11 @@ pub async fn update_lobby(
         Some(e) => e,
         None => old.lobby.events.0,
     };
+    events.validate(&settings)?;
 
     sqlx::query_as!(Lobby,
         // language=PostgreSQL
-        r#"update "lobby" set name = $1, password = $2, connect_code = $3, code_use_times = $4, max_players = $5, settings = $6, public = $7, events = $8 where id = $9  returning id, name, password, public, connect_code, code_use_times, max_players, started, owner_id, settings as "settings: sqlx::types::Json<Settings>", events as "events: sqlx::types::Json<GameEvents>""#,
+        r#"update "lobby" set name = $1, password = $2, connect_code = $3, code_use_times = $4, max_players = $5, settings = $6, public = $7, events = $8 where id = $9  returning id, name, password, public, connect_code, code_use_times, max_players, started, paused, owner_id, settings as "settings: sqlx::types::Json<Settings>", events as "events: sqlx::types::Json<GameEvents>""#,
         payload.name,
         payload.password,
         connect_code,
@@ -355,7 +359,11 @@ pub async fn update_lobby_classes(
 ) -> Result<(), AppError> {
     //TODO: check classes
     match state.lobbies.write().await.get_mut(&game_id) {
-        Some(lobby) => lobby.round_state.player_classes = classes.clone(),
+        // a running game keeps the classes it started with
+        Some(lobby) if lobby.started => {
+            return Err(AppError::BadRequest("game already started".to_string()))
+        }
+        Some(lobby) => lobby.player_classes = classes.clone(),
         None => {
             return Err(AppError::BadRequest(
                 "game not found with this id".to_string(),
@@ -1,4 +1,4 @@
-use std::{collections::BTreeMap, sync::Arc};
+use std::sync::Arc;
 
 use axum::{
     extract::{Extension, Path, Query},
@@ -9,7 +9,7 @@ use uuid::Uuid;
 
 use crate::{
     auth::{Auth, AuthAdmin},
-    entities::{Lobby, User, UserRole},
+    entities::{BotPolicy, Lobby, User, UserRole},
     error::AppError,
     user::user::lock_lobby_tables,
     websockets::EventMessages,
@@ -17,7 +17,10 @@ use crate::{
 };
 
 use super::{
-    game::{start_new_game, GameEnd},
+    game::{
+        abort_game, pause_game, play_new_game, resume_game, rewind_game, set_player_bot,
+        start_new_game, GameEnd, StartGame, StartGameBody,
+    },
     lobby::{
         create_lobby, get_lobby, get_lobby_players, get_lobby_response, get_lobby_transaction,
         send_broadcast_msg, update_lobby, CreateLobby, LobbiesQuery, LobbiesType, LobbyResponse,
@@ -186,7 +189,7 @@ pub async fn start_game_endpoint(
     Path(id): Path<Uuid>,
     Extension(ref db): Extension<PgPool>,
     Extension(state): Extension<Arc<State>>,
-    Json(player_classes): Json<BTreeMap<Uuid, u32>>,
+    Json(payload): Json<StartGameBody>,
     _auth: AuthAdmin,
 ) -> Result<(), AppError> {
     let lobby = get_lobby(id, db).await?;
@@ -214,15 +217,40 @@ pub async fn start_game_endpoint(
 
     let players = get_lobby_players(id, &mut tx).await?;
 
-    start_new_game(&mut tx, id, lobby, players, player_classes, &state).await?;
+    let mut start: StartGame = payload.into();
+    // classes picked in the lobby, the ones in the body win
+    if let Some(lobby_state) = state.lobbies.read().await.get(&id) {
+        for (player, class) in &lobby_state.player_classes {
+            start.player_classes.entry(*player).or_insert(*class);
+        }
+    }
+
+    let engines = start_new_game(&mut tx, id, lobby, players, start).await?;
 
     tx.commit()
         .await
         .map_err(|e| AppError::DbErr(e.to_string()))?;
 
-    Ok(())
+    play_new_game(id, engines, &state, db).await
+}
+
+pub async fn set_bot_endpoint(
+    Path((id, player)): Path<(Uuid, Uuid)>,
+    Extension(ref db): Extension<PgPool>,
+    Extension(state): Extension<Arc<State>>,
+    Json(policy): Json<Option<BotPolicy>>,
+    _auth: AuthAdmin,
+) -> Result<(), AppError> {
+    let lobby = get_lobby(id, db).await?;
+
+    if !lobby.started {
+        return Err(AppError::GameNotStarted(lobby.name));
+    }
+
+    set_player_bot(id, player, policy, &state, db).await
 }
 
+/// Aborts the game, players get the results of the rounds played so far.
 pub async fn stop_game_endpoint(
     Path(id): Path<Uuid>,
     Extension(ref db): Extension<PgPool>,
@@ -232,7 +260,7 @@ pub async fn stop_game_endpoint(
     let lobby = get_lobby(id, db).await?;
 
     if !lobby.started {
-        return Err(AppError::GameStarted(lobby.name));
+        return Err(AppError::GameNotStarted(lobby.name));
     }
 
     let mut tx = db
@@ -244,7 +272,7 @@ pub async fn stop_game_endpoint(
 
     sqlx::query!(
         // language=PostgreSQL
-        r#"update "lobby" set started = $1 where id = $2 "#,
+        r#"update "lobby" set started = $1, paused = $1 where id = $2 "#,
         false,
         id
     )
@@ -252,6 +280,87 @@ pub async fn stop_game_endpoint(
     .await
     .map_err(|e| AppError::DbErr(e.to_string()))?;
 
+    tx.commit()
+        .await
+        .map_err(|e| AppError::DbErr(e.to_string()))?;
+
+    abort_game(id, &state, db).await
+}
+
+pub async fn pause_game_endpoint(
+    Path(id): Path<Uuid>,
+    Extension(ref db): Extension<PgPool>,
+    Extension(state): Extension<Arc<State>>,
+    _auth: AuthAdmin,
+) -> Result<(), AppError> {
+    let lobby = get_lobby(id, db).await?;
+
+    if !lobby.started {
+        return Err(AppError::GameNotStarted(lobby.name));
+    }
+
+    if lobby.paused {
+        return Err(AppError::GamePaused(lobby.name));
+    }
+
+    set_lobby_paused(id, true, db).await?;
+    pause_game(id, &state).await
+}
+
+pub async fn resume_game_endpoint(
+    Path(id): Path<Uuid>,
+    Extension(ref db): Extension<PgPool>,
+    Extension(state): Extension<Arc<State>>,
+    _auth: AuthAdmin,
+) -> Result<(), AppError> {
+    let lobby = get_lobby(id, db).await?;
+
+    if !lobby.started {
+        return Err(AppError::GameNotStarted(lobby.name));
+    }
+
+    if !lobby.paused {
+        return Err(AppError::BadRequest("game is not paused".to_string()));
+    }
+
+    set_lobby_paused(id, false, db).await?;
+    resume_game(id, &state, db).await
+}
+
+/// Rolls the game back to the start of `round`, for when a player misclicked.
+pub async fn rewind_game_endpoint(
+    Path((id, round)): Path<(Uuid, i64)>,
+    Extension(ref db): Extension<PgPool>,
+    Extension(state): Extension<Arc<State>>,
+    _auth: AuthAdmin,
+) -> Result<(), AppError> {
+    let lobby = get_lobby(id, db).await?;
+
+    if !lobby.started {
+        return Err(AppError::GameNotStarted(lobby.name));
+    }
+
+    rewind_game(id, round, &state, db).await
+}
+
+async fn set_lobby_paused(id: Uuid, paused: bool, db: &PgPool) -> Result<(), AppError> {
+    let mut tx = db
+        .begin()
+        .await
+        .map_err(|e| AppError::DbErr(e.to_string()))?;
+
+    lock_lobby_tables(&mut tx).await?;
+
+    sqlx::query!(
+        // language=PostgreSQL
+        r#"update "lobby" set paused = $1 where id = $2 "#,
+        paused,
+        id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|e| AppError::DbErr(e.to_string()))?;
+
     tx.commit()
         .await
         .map_err(|e| AppError::DbErr(e.to_string()))?;
@@ -1,3 +1,5 @@
+pub mod bots;
+pub mod engine;
 pub mod game;
 pub mod lobby;
 pub mod lobby_endpoints;
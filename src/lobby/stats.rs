@@ -1,5 +1,5 @@
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
     sync::Arc,
 };
 
@@ -10,7 +10,10 @@ use uuid::Uuid;
 
 use crate::{
     auth::Auth,
-    entities::{Flow, GameState, Order, UserState},
+    entities::{
+        DelayedShipment, Disruption, Flow, GameState, Order, ProductRound, Settings, Stock,
+        UserState,
+    },
     error::AppError,
     State,
 };
@@ -71,77 +74,147 @@ pub async fn players_stats(
         UserStatsType::ReceivedOrder,
         UserStatsType::SpentMoney,
         UserStatsType::BackOrder,
+        UserStatsType::Performance,
     ];
 
     Ok(Json(get_player_stats(game_id, db, stats).await?))
 }
 
+pub async fn teams_stats(
+    Path(game_id): Path<Uuid>,
+    Extension(ref db): Extension<PgPool>,
+    auth: Auth,
+) -> Result<Json<HashMap<String, HashMap<Uuid, Vec<i64>>>>, AppError> {
+    let lobby = get_lobby(game_id, db).await?;
+    if !lobby.started {
+        return Err(AppError::GameNotStarted(
+            "can't get stats for game not started".to_string(),
+        ));
+    }
+    let users = get_lobby_users(game_id, db).await?;
+    if !users.iter().any(|x| x.id == auth.user_id) {
+        return Err(AppError::Unauthorized(
+            "not connected to the game".to_string(),
+        ));
+    }
+
+    let stats = vec![
+        UserStatsType::MagazineState,
+        UserStatsType::Money,
+        UserStatsType::PlacedOrder,
+        UserStatsType::ReceivedOrder,
+        UserStatsType::SpentMoney,
+        UserStatsType::BackOrder,
+        UserStatsType::Performance,
+    ];
+
+    Ok(Json(get_teams_stats(game_id, db, stats).await?))
+}
+
 pub async fn get_player_stats(
     game_id: Uuid,
     db: &PgPool,
     stats_types: Vec<UserStatsType>,
 ) -> Result<HashMap<String, HashMap<Uuid, Vec<i64>>>, AppError> {
-    let games_states = sqlx::query_as!(GameState,
+    let games_states = get_games_states(game_id, db).await?;
+
+    let mut stats = HashMap::new();
+    for stats_type in stats_types {
+        for (name, extractor) in extractors(&stats_type, &games_states) {
+            get_stats_for_type(extractor, name, &games_states, &mut stats);
+        }
+    }
+    Ok(stats)
+}
+
+/// Stats of every team summed up over its players, so chains can be compared.
+pub async fn get_teams_stats(
+    game_id: Uuid,
+    db: &PgPool,
+    stats_types: Vec<UserStatsType>,
+) -> Result<HashMap<String, HashMap<Uuid, Vec<i64>>>, AppError> {
+    let games_states = get_games_states(game_id, db).await?;
+
+    let mut stats = HashMap::new();
+    for stats_type in stats_types {
+        for (name, extractor) in extractors(&stats_type, &games_states) {
+            let mut type_stats: HashMap<Uuid, Vec<i64>> = HashMap::new();
+            for round_state in &games_states {
+                let value = round_state.user_states.0.values().map(&extractor).sum();
+                type_stats
+                    .entry(round_state.team_id)
+                    .or_default()
+                    .push(value);
+            }
+            stats.insert(name, type_stats);
+        }
+    }
+    Ok(stats)
+}
+
+async fn get_games_states(game_id: Uuid, db: &PgPool) -> Result<Vec<GameState>, AppError> {
+    sqlx::query_as!(GameState,
         r#"
-        select id, round, user_states as "user_states: sqlx::types::Json<BTreeMap<Uuid, UserState>>", round_orders as "round_orders: sqlx::types::Json<BTreeMap<Uuid, Order>>", flow as "flow: sqlx::types::Json<Flow>", demand, supply, send_orders as "send_orders: sqlx::types::Json<BTreeMap<Uuid, Order>>", players_classes as "players_classes: sqlx::types::Json<BTreeMap<Uuid, u32>>", game_id
+        select id, round, user_states as "user_states: sqlx::types::Json<BTreeMap<Uuid, UserState>>", round_orders as "round_orders: sqlx::types::Json<BTreeMap<Uuid, Order>>", flow as "flow: sqlx::types::Json<Flow>", demand, supply, send_orders as "send_orders: sqlx::types::Json<BTreeMap<Uuid, Order>>", players_classes as "players_classes: sqlx::types::Json<BTreeMap<Uuid, u32>>", game_id, team_id, team_name, seed, supply_ordered, supply_delivered, products as "products: sqlx::types::Json<BTreeMap<String, ProductRound>>", fired_events as "fired_events: sqlx::types::Json<BTreeMap<usize, Vec<i64>>>", disruptions as "disruptions: sqlx::types::Json<Vec<Disruption>>", delayed_shipments as "delayed_shipments: sqlx::types::Json<Vec<DelayedShipment>>", settings as "settings: sqlx::types::Json<Settings>"
         from "game_state"
         where game_id = $1
-        order by round"#,
+        order by team_id, round"#,
         game_id
     ).fetch_all(db)
     .await
-    .map_err(|e| AppError::DbErr(e.to_string()))?;
+    .map_err(|e| AppError::DbErr(e.to_string()))
+}
 
-    let mut stats = HashMap::new();
+type Extractor = Box<dyn Fn(&UserState) -> i64>;
 
-    for stats_type in stats_types {
-        match stats_type {
-            UserStatsType::Money => {
-                get_stats_for_type(|u| u.money, "money".to_string(), &games_states, &mut stats)
-            }
-            UserStatsType::Performance => get_stats_for_type(
-                |u| u.performance,
-                "performance".to_string(),
-                &games_states,
-                &mut stats,
-            ),
-            UserStatsType::MagazineState => get_stats_for_type(
-                |u| u.magazine_state,
-                "magazine_state".to_string(),
-                &games_states,
-                &mut stats,
-            ),
-            UserStatsType::PlacedOrder => get_stats_for_type(
-                |u| u.placed_order.cost,
-                "placed_order".to_string(),
-                &games_states,
-                &mut stats,
-            ),
-            UserStatsType::ReceivedOrder => get_stats_for_type(
-                |u| u.received_order.cost,
-                "received_order".to_string(),
-                &games_states,
-                &mut stats,
-            ),
-            UserStatsType::BackOrder => get_stats_for_type(
-                |u| u.back_order_sum,
-                "back_order".to_string(),
-                &games_states,
-                &mut stats,
-            ),
-            UserStatsType::SpentMoney => get_stats_for_type(
-                |u| u.spent_money,
-                "spent_money".to_string(),
-                &games_states,
-                &mut stats,
-            ),
+/// Named extractors of a stat. Stats of the stock are given for the main product
+/// and for every additional product as `<stat>:<product>`.
+fn extractors(stats_type: &UserStatsType, games_states: &[GameState]) -> Vec<(String, Extractor)> {
+    let (extractor, name) = stats_extractor(stats_type);
+    let mut extractors: Vec<(String, Extractor)> = vec![(name.to_string(), Box::new(extractor))];
+
+    if let Some(stock_extractor) = stock_extractor(stats_type) {
+        let products: BTreeSet<&String> = games_states
+            .iter()
+            .flat_map(|g| g.user_states.0.values())
+            .flat_map(|u| u.products.keys())
+            .collect();
+        for product in products {
+            let key = product.clone();
+            extractors.push((
+                format!("{}:{}", name, product),
+                Box::new(move |u| u.products.get(&key).map_or(0, stock_extractor)),
+            ));
         }
     }
-    Ok(stats)
+
+    extractors
+}
+
+fn stats_extractor(stats_type: &UserStatsType) -> (fn(&UserState) -> i64, &'static str) {
+    match stats_type {
+        UserStatsType::Money => (|u| u.money, "money"),
+        UserStatsType::Performance => (|u| u.performance, "performance"),
+        UserStatsType::MagazineState => (|u| u.stock.magazine_state, "magazine_state"),
+        UserStatsType::PlacedOrder => (|u| u.stock.placed_order.cost, "placed_order"),
+        UserStatsType::ReceivedOrder => (|u| u.stock.received_order.cost, "received_order"),
+        UserStatsType::BackOrder => (|u| u.stock.back_order_sum, "back_order"),
+        UserStatsType::SpentMoney => (|u| u.spent_money, "spent_money"),
+    }
+}
+
+fn stock_extractor(stats_type: &UserStatsType) -> Option<fn(&Stock) -> i64> {
+    match stats_type {
+        UserStatsType::MagazineState => Some(|s| s.magazine_state),
+        UserStatsType::PlacedOrder => Some(|s| s.placed_order.cost),
+        UserStatsType::ReceivedOrder => Some(|s| s.received_order.cost),
+        UserStatsType::BackOrder => Some(|s| s.back_order_sum),
+        _ => None,
+    }
 }
 
 fn get_stats_for_type(
-    extractor: fn(&UserState) -> i64,
+    extractor: impl Fn(&UserState) -> i64,
     stat_name: String,
     games_states: &Vec<GameState>,
     stats: &mut HashMap<String, HashMap<Uuid, Vec<i64>>>,
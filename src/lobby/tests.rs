@@ -1,19 +1,42 @@
 use axum::http::StatusCode;
 
+use rand::{rngs::StdRng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use sqlx::PgPool;
-use std::str;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    str,
+};
 
 use tower::Service;
 use tower::ServiceExt;
 use uuid::Uuid;
 
 use crate::{
-    auth::AuthPayload,
+    auth::{AuthBody, AuthPayload},
     common_tests::{
         authorize_admin, authorize_user, build_request, create_test_app, create_test_lobbies,
     },
-    entities::{GameEvents, Lobby, Settings, User, UserRole},
-    lobby::lobby::{CreateLobby, LobbyResponse},
+    entities::{
+        ActionTarget, BankruptcyOutcome, BankruptcyPolicy, Bot, BotPolicy, ChainPosition,
+        ClassLink, Comparison, DefaultOrderPolicy, DemandPromotion, DemandSegment, Disruption,
+        EventAction, EventCondition, Flow, FlowLink, GameEvent, GameEvents, GeneratedOrderStyle,
+        ListEnd, Lobby, MetBy, Order, OrderSplit, PerformanceMetric, PlayerGroup, Product,
+        Resource, RoundCosts, Settings, SettingsPatch, Supply, User, UserRole, UserState,
+        Visibility, WarehouseOverflow,
+    },
+    error::AppError,
+    lobby::{
+        bots::{bot_order, BotView},
+        engine::{generate_demand, order_players, round_rng, start_value, GameEngine},
+        game::{
+            get_game_state, process_user_round_end_message, visible_players, BotSetup, GameEnd,
+            GameUpdate, PlayerOrder, StartGame, TeamSetup, UserEndRound,
+        },
+        lobby::{get_lobby, CreateLobby, LobbyResponse},
+    },
+    websockets::EventMessages,
+    RoundState,
 };
 
 #[sqlx::test(fixtures("users"))]
@@ -172,7 +195,7 @@ async fn test_create_lobby(db: PgPool) {
 
     let lobby = sqlx::query_as!(Lobby,
         // language=PostgreSQL
-        r#"select id, name, password, public, connect_code, code_use_times, max_players, started, owner_id, settings as "settings: sqlx::types::Json<Settings>", events as "events: sqlx::types::Json<GameEvents>" from "lobby" where id = $1"#,
+        r#"select id, name, password, public, connect_code, code_use_times, max_players, started, paused, owner_id, settings as "settings: sqlx::types::Json<Settings>", events as "events: sqlx::types::Json<GameEvents>" from "lobby" where id = $1"#,
         returned_lobby.lobby.id
     )
     .fetch_one(&db)
@@ -231,7 +254,7 @@ async fn test_get_public_private_all_lobbies(db: PgPool) {
 
     let lobbies = sqlx::query_as!(Lobby,
         // language=PostgreSQL
-        r#"select id, name, password, public, connect_code, code_use_times, max_players, started, owner_id, settings as "settings: sqlx::types::Json<Settings>", events as "events: sqlx::types::Json<GameEvents>" from "lobby" where public = true"#,
+        r#"select id, name, password, public, connect_code, code_use_times, max_players, started, paused, owner_id, settings as "settings: sqlx::types::Json<Settings>", events as "events: sqlx::types::Json<GameEvents>" from "lobby" where public = true"#,
     )
     .fetch_all(&db)
     .await
@@ -265,7 +288,7 @@ async fn test_get_public_private_all_lobbies(db: PgPool) {
 
     let lobbies = sqlx::query_as!(Lobby,
         // language=PostgreSQL
-        r#"select id, name, password, public, connect_code, code_use_times, max_players, started, owner_id, settings as "settings: sqlx::types::Json<Settings>", events as "events: sqlx::types::Json<GameEvents>" from "lobby" where public = false"#,
+        r#"select id, name, password, public, connect_code, code_use_times, max_players, started, paused, owner_id, settings as "settings: sqlx::types::Json<Settings>", events as "events: sqlx::types::Json<GameEvents>" from "lobby" where public = false"#,
     )
     .fetch_all(&db)
     .await
@@ -299,7 +322,7 @@ async fn test_get_public_private_all_lobbies(db: PgPool) {
 
     let lobbies = sqlx::query_as!(Lobby,
         // language=PostgreSQL
-        r#"select id, name, password, public, connect_code, code_use_times, max_players, started, owner_id, settings as "settings: sqlx::types::Json<Settings>", events as "events: sqlx::types::Json<GameEvents>" from "lobby""#,
+        r#"select id, name, password, public, connect_code, code_use_times, max_players, started, paused, owner_id, settings as "settings: sqlx::types::Json<Settings>", events as "events: sqlx::types::Json<GameEvents>" from "lobby""#,
     )
     .fetch_all(&db)
     .await
@@ -346,7 +369,7 @@ async fn test_delete_lobby(db: PgPool) {
 
     let lobbies = sqlx::query_as!(Lobby,
         // language=PostgreSQL
-        r#"select id, name, password, public, connect_code, code_use_times, max_players, started, owner_id, settings as "settings: sqlx::types::Json<Settings>", events as "events: sqlx::types::Json<GameEvents>" from "lobby""#,
+        r#"select id, name, password, public, connect_code, code_use_times, max_players, started, paused, owner_id, settings as "settings: sqlx::types::Json<Settings>", events as "events: sqlx::types::Json<GameEvents>" from "lobby""#,
     )
     .fetch_all(&db)
     .await
@@ -356,3 +379,2399 @@ async fn test_delete_lobby(db: PgPool) {
 
     assert_eq!(state.lobbies.read().await.len(), 1);
 }
+
+async fn post_lobby(app: &mut axum::Router, auth: &AuthBody, uri: String) -> StatusCode {
+    let opt: Option<&AuthPayload> = None;
+    let response = app
+        .ready()
+        .await
+        .unwrap()
+        .call(build_request("POST", uri.as_str(), opt, Some(auth)))
+        .await
+        .unwrap();
+
+    response.status()
+}
+
+#[sqlx::test(fixtures("users"))]
+async fn test_pause_resume_abort_game(db: PgPool) {
+    let (app, state) = create_test_app(db.clone()).await;
+
+    let (auth, mut app) = authorize_admin(app).await;
+
+    let (lobby_1, _) = create_test_lobbies(
+        db.clone(),
+        state.clone(),
+        "alice",
+        "51b374f1-93ae-4c5c-89dd-611bda8412ce",
+    )
+    .await;
+    let id = lobby_1.id;
+
+    let status = post_lobby(&mut app, &auth, format!("/lobby/{}/pause", id)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    sqlx::query!(r#"update "lobby" set started = true where id = $1"#, id)
+        .execute(&db)
+        .await
+        .unwrap();
+    let (round_state, _) = start_test_engine().finish();
+    let mut rx = match state.lobbies.write().await.get_mut(&id) {
+        Some(lobby_state) => {
+            lobby_state.started = true;
+            lobby_state.teams.insert(round_state.team.id, round_state);
+            lobby_state.sender.subscribe()
+        }
+        None => panic!("expected a lobby state"),
+    };
+    let round_end = UserEndRound {
+        placed_order: test_order(4),
+        product_orders: BTreeMap::new(),
+    };
+
+    let status = post_lobby(&mut app, &auth, format!("/lobby/{}/pause", id)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(get_lobby(id, &db).await.unwrap().paused);
+    let status = post_lobby(&mut app, &auth, format!("/lobby/{}/pause", id)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let res =
+        process_user_round_end_message(id, RETAILER, round_end.clone(), state.clone(), &db).await;
+    assert!(matches!(res, Err(AppError::GamePaused(_))));
+
+    let status = post_lobby(&mut app, &auth, format!("/lobby/{}/resume", id)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(!get_lobby(id, &db).await.unwrap().paused);
+    process_user_round_end_message(id, RETAILER, round_end, state.clone(), &db)
+        .await
+        .unwrap();
+
+    let status = post_lobby(&mut app, &auth, format!("/lobby/{}/stop", id)).await;
+    assert_eq!(status, StatusCode::OK);
+    let lobby = get_lobby(id, &db).await.unwrap();
+    assert!(!lobby.started && !lobby.paused);
+    match state.lobbies.read().await.get(&id) {
+        Some(lobby_state) => assert!(!lobby_state.started && lobby_state.teams.is_empty()),
+        None => panic!("expected a lobby state"),
+    }
+
+    let mut controls = Vec::new();
+    let mut game_end = false;
+    while let Ok(msg) = rx.try_recv() {
+        match msg {
+            EventMessages::GamePaused | EventMessages::GameResumed | EventMessages::GameAborted => {
+                controls.push(msg)
+            }
+            EventMessages::Team(_, msg) => game_end |= matches!(*msg, EventMessages::GameEnd(_)),
+            _ => {}
+        }
+    }
+    let expected = vec![
+        EventMessages::GamePaused,
+        EventMessages::GameResumed,
+        EventMessages::GameAborted,
+    ];
+    assert_eq!(controls, expected);
+    assert!(game_end);
+}
+
+#[sqlx::test(fixtures("users"))]
+async fn test_rewind_game(db: PgPool) {
+    let (app, state) = create_test_app(db.clone()).await;
+
+    let (auth, mut app) = authorize_admin(app).await;
+
+    let (lobby_1, _) = create_test_lobbies(
+        db.clone(),
+        state.clone(),
+        "alice",
+        "51b374f1-93ae-4c5c-89dd-611bda8412ce",
+    )
+    .await;
+    let id = lobby_1.id;
+
+    sqlx::query!(r#"update "lobby" set started = true where id = $1"#, id)
+        .execute(&db)
+        .await
+        .unwrap();
+    let (round_state, _) = start_test_engine().finish();
+    let team = round_state.team.id;
+    if let Some(lobby_state) = state.lobbies.write().await.get_mut(&id) {
+        lobby_state.started = true;
+        lobby_state.teams.insert(team, round_state);
+    }
+
+    for value in [4, 40] {
+        for player in [FACTORY, RETAILER] {
+            let round_end = UserEndRound {
+                placed_order: test_order(value),
+                product_orders: BTreeMap::new(),
+            };
+            process_user_round_end_message(id, player, round_end, state.clone(), &db)
+                .await
+                .unwrap();
+        }
+    }
+    let snapshot = get_game_state(id, team, 1, &db).await.unwrap().unwrap();
+    assert!(get_game_state(id, team, 2, &db).await.unwrap().is_some());
+
+    let mut rx = state.lobbies.read().await[&id].sender.subscribe();
+    let status = post_lobby(&mut app, &auth, format!("/lobby/{}/rewind/5", id)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let status = post_lobby(&mut app, &auth, format!("/lobby/{}/rewind/1", id)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    match state.lobbies.read().await.get(&id) {
+        Some(lobby_state) => {
+            let round_state = &lobby_state.teams[&team];
+            assert_eq!(round_state.round, 1);
+            assert_eq!(round_state.players_finished, 0);
+            assert_eq!(round_state.users_states, snapshot.user_states.0);
+            assert_eq!(
+                Some(&round_state.settings),
+                snapshot.settings.as_ref().map(|s| &s.0)
+            );
+        }
+        None => panic!("expected a lobby state"),
+    }
+    assert!(get_game_state(id, team, 2, &db).await.unwrap().is_none());
+
+    let mut round_start = None;
+    while let Ok(msg) = rx.try_recv() {
+        if let EventMessages::Team(_, msg) = msg {
+            if let EventMessages::RoundStart(update) = *msg {
+                round_start = Some(update.round);
+            }
+        }
+    }
+    assert_eq!(round_start, Some(1));
+}
+
+#[sqlx::test(fixtures("users"))]
+async fn test_start_game_legacy_body(db: PgPool) {
+    let (app, state) = create_test_app(db.clone()).await;
+
+    let (auth, mut app) = authorize_admin(app).await;
+
+    let (lobby_1, _) = create_test_lobbies(
+        db.clone(),
+        state.clone(),
+        "alice",
+        "51b374f1-93ae-4c5c-89dd-611bda8412ce",
+    )
+    .await;
+    let id = lobby_1.id;
+
+    let bob = Uuid::parse_str("c994b839-84f4-4509-ad49-59119133d6f5").unwrap();
+    let bob3 = Uuid::parse_str("c994b839-84f4-4509-ad49-59429133d6f5").unwrap();
+    sqlx::query!(
+        r#"update "lobby" set settings = $1 where id = $2"#,
+        sqlx::types::Json(engine_test_settings()) as _,
+        id
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"update "user" set game_id = $1 where id = $2 or id = $3"#,
+        id,
+        bob,
+        bob3
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    // the body sent before teams, bots and seeds were added
+    let player_classes = BTreeMap::from([(bob, 0), (bob3, 1)]);
+    let response = app
+        .ready()
+        .await
+        .unwrap()
+        .call(build_request(
+            "POST",
+            format!("/lobby/{}/start", id).as_str(),
+            Some(&player_classes),
+            Some(&auth),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    match state.lobbies.read().await.get(&id) {
+        Some(lobby_state) => {
+            assert!(lobby_state.started);
+            assert_eq!(lobby_state.teams.len(), 1);
+            let round_state = lobby_state.teams.values().next().unwrap();
+            assert_eq!(round_state.player_classes, player_classes);
+        }
+        None => panic!("expected a lobby state"),
+    }
+    assert!(get_lobby(id, &db).await.unwrap().started);
+}
+
+#[sqlx::test(fixtures("users"))]
+async fn test_start_game_lobby_classes(db: PgPool) {
+    let (app, state) = create_test_app(db.clone()).await;
+
+    let (auth, mut app) = authorize_admin(app).await;
+
+    let (lobby_1, _) = create_test_lobbies(
+        db.clone(),
+        state.clone(),
+        "alice",
+        "51b374f1-93ae-4c5c-89dd-611bda8412ce",
+    )
+    .await;
+    let id = lobby_1.id;
+
+    let bob = Uuid::parse_str("c994b839-84f4-4509-ad49-59119133d6f5").unwrap();
+    let bob3 = Uuid::parse_str("c994b839-84f4-4509-ad49-59429133d6f5").unwrap();
+    sqlx::query!(
+        r#"update "lobby" set settings = $1 where id = $2"#,
+        sqlx::types::Json(engine_test_settings()) as _,
+        id
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+    sqlx::query!(
+        r#"update "user" set game_id = $1 where id = $2 or id = $3"#,
+        id,
+        bob,
+        bob3
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    crate::lobby::lobby::update_lobby_classes(&state, id, BTreeMap::from([(bob, 1), (bob3, 1)]))
+        .await
+        .unwrap();
+
+    // only bob's class is given with the start, bob3 keeps the lobby one
+    let player_classes = BTreeMap::from([(bob, 0)]);
+    let response = app
+        .ready()
+        .await
+        .unwrap()
+        .call(build_request(
+            "POST",
+            format!("/lobby/{}/start", id).as_str(),
+            Some(&player_classes),
+            Some(&auth),
+        ))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    match state.lobbies.read().await.get(&id) {
+        Some(lobby_state) => {
+            let round_state = lobby_state.teams.values().next().unwrap();
+            assert_eq!(
+                round_state.player_classes,
+                BTreeMap::from([(bob, 0), (bob3, 1)])
+            );
+        }
+        None => panic!("expected a lobby state"),
+    }
+
+    let result = crate::lobby::lobby::update_lobby_classes(
+        &state,
+        id,
+        BTreeMap::from([(bob, 1), (bob3, 0)]),
+    )
+    .await;
+    assert!(result.is_err());
+}
+
+#[sqlx::test(fixtures("users"))]
+async fn test_restore_after_patch_event(db: PgPool) {
+    let (_, state) = create_test_app(db.clone()).await;
+
+    let (lobby_1, _) = create_test_lobbies(
+        db.clone(),
+        state.clone(),
+        "alice",
+        "51b374f1-93ae-4c5c-89dd-611bda8412ce",
+    )
+    .await;
+    let id = lobby_1.id;
+
+    let patch: SettingsPatch = serde_json::from_str(r#"{"round_time_limit": 30}"#).unwrap();
+    let mut events = event_at_round_one("slow", EventAction::PatchSettings { patch });
+    events.events[0].run_once = true;
+    sqlx::query!(
+        r#"update "lobby" set started = true, events = $1 where id = $2"#,
+        sqlx::types::Json(events) as _,
+        id
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+    let (round_state, _) = start_test_engine().finish();
+    let team = round_state.team.id;
+    if let Some(lobby_state) = state.lobbies.write().await.get_mut(&id) {
+        lobby_state.started = true;
+        lobby_state.teams.insert(team, round_state);
+    }
+
+    for player in [FACTORY, RETAILER] {
+        let round_end = UserEndRound {
+            placed_order: test_order(4),
+            product_orders: BTreeMap::new(),
+        };
+        process_user_round_end_message(id, player, round_end, state.clone(), &db)
+            .await
+            .unwrap();
+    }
+    let played = state.lobbies.read().await[&id].teams[&team].clone();
+    assert_eq!(played.settings.round_time_limit, Some(30));
+
+    // a restart keeps the patch of the event that won't fire again
+    let (_, restored) = create_test_app(db.clone()).await;
+    crate::restore_lobbies(&restored, &db).await;
+    let round_state = restored.lobbies.read().await[&id].teams[&team].clone();
+    assert_eq!(round_state.round, 1);
+    assert_eq!(round_state.settings, played.settings);
+    assert_eq!(round_state.fired_events, played.fired_events);
+}
+
+const FACTORY: Uuid = Uuid::from_u128(1);
+const RETAILER: Uuid = Uuid::from_u128(2);
+const SECOND: Uuid = Uuid::from_u128(3);
+
+fn engine_test_settings() -> Settings {
+    let per_class = |factory: i64, retailer: i64| BTreeMap::from([(0, factory), (1, retailer)]);
+
+    Settings {
+        max_rounds: 10,
+        user_classes: vec![0, 1],
+        incoming_start_queue: BTreeMap::from([(0, vec![4]), (1, vec![4])]),
+        requested_start_queue: BTreeMap::from([(0, vec![4]), (1, vec![8])]),
+        demand_style: crate::entities::GeneratedOrderStyle::Linear {
+            start: 4,
+            increase: 1,
+        },
+        resource_basic_price: 1,
+        resource_price: per_class(2, 3),
+        start_money: per_class(100, 100),
+        start_magazine: per_class(10, 2),
+        magazine_cost: per_class(1, 1),
+        fix_order_cost: per_class(5, 5),
+        ..Settings::default()
+    }
+}
+
+fn start_test_engine() -> GameEngine {
+    start_test_engine_with(engine_test_settings())
+}
+
+fn start_test_engine_with(settings: Settings) -> GameEngine {
+    let players_classes = BTreeMap::from([(FACTORY, 0), (RETAILER, 1)]);
+    let engine =
+        GameEngine::start_game(settings, &[FACTORY, RETAILER], players_classes, None).unwrap();
+    let (round_state, _) = engine.finish();
+    GameEngine::new(round_state)
+}
+
+fn engine_user_state(engine: &GameEngine, player: Uuid) -> UserState {
+    let (round_state, _) = engine.clone().finish();
+    round_state.users_states.get(&player).unwrap().clone()
+}
+
+fn test_order(value: i64) -> Order {
+    Order {
+        value,
+        cost: value,
+        ..Order::default()
+    }
+}
+
+#[test]
+fn test_engine_start_game() {
+    let players_classes = BTreeMap::from([(FACTORY, 0), (RETAILER, 1)]);
+    let engine = GameEngine::start_game(
+        engine_test_settings(),
+        &[FACTORY, RETAILER],
+        players_classes,
+        None,
+    )
+    .unwrap();
+    let (round_state, events) = engine.finish();
+
+    assert_eq!(round_state.flow.get_recipients(&FACTORY), vec![RETAILER]);
+    assert!(round_state.flow.is_source(&FACTORY));
+    assert!(round_state.flow.is_sink(&RETAILER));
+    assert_eq!(round_state.demand, 4);
+    assert_eq!(round_state.players, 2);
+
+    let retailer = round_state.users_states.get(&RETAILER).unwrap();
+    assert_eq!(retailer.money, 100);
+    assert_eq!(retailer.stock.magazine_state, 2);
+    assert_eq!(retailer.stock.incoming_orders[0].sender, FACTORY);
+    assert_eq!(retailer.stock.requested_orders[0].recipient, Uuid::nil());
+
+    assert!(matches!(events.as_slice(), [EventMessages::GameStart(_)]));
+}
+
+#[test]
+fn test_engine_player_round_backlog_and_costs() {
+    let mut engine = start_test_engine();
+
+    engine.end_player_round(RETAILER, test_order(6)).unwrap();
+
+    let retailer = engine_user_state(&engine, RETAILER);
+    // magazine 2 + delivery 4 = 6 in stock for a request of 8
+    assert_eq!(retailer.stock.magazine_state, 0);
+    assert_eq!(retailer.stock.back_order_sum, 2);
+    assert_eq!(retailer.stock.sent_orders[0].value, 6);
+    assert_eq!(retailer.stock.sent_orders[0].cost, 6 * 3 + 5);
+    // order cost 6 + storage of 2 units
+    assert_eq!(retailer.money, 100 - 6 - 2);
+    assert_eq!(retailer.stock.placed_order.sender, FACTORY);
+    assert!(!engine.round_finished());
+
+    engine.end_player_round(FACTORY, test_order(4)).unwrap();
+
+    let factory = engine_user_state(&engine, FACTORY);
+    assert_eq!(factory.stock.magazine_state, 10);
+    assert_eq!(factory.stock.back_order_sum, 0);
+    assert_eq!(factory.stock.placed_order.sender, Uuid::nil());
+    assert!(engine.round_finished());
+}
+
+#[test]
+fn test_engine_ships_exact_stock_and_acks_once() {
+    let mut settings = engine_test_settings();
+    settings.start_magazine = BTreeMap::from([(0, 10), (1, 4)]);
+    let mut engine = start_test_engine_with(settings);
+
+    engine.end_player_round(RETAILER, test_order(6)).unwrap();
+
+    let retailer = engine_user_state(&engine, RETAILER);
+    // magazine 4 + delivery 4 covers the request of 8 exactly
+    assert_eq!(retailer.stock.magazine_state, 0);
+    assert_eq!(retailer.stock.back_order_sum, 0);
+    assert_eq!(retailer.stock.sent_orders[0].value, 8);
+
+    // a second order in the same round is rejected without touching the state
+    assert!(engine.end_player_round(RETAILER, test_order(1)).is_err());
+    assert_eq!(engine_user_state(&engine, RETAILER), retailer);
+
+    // the ack is only sent for the order that was processed
+    let (round_state, events) = engine.finish();
+    assert_eq!(round_state.players_finished, 1);
+    let acks = events
+        .iter()
+        .filter(|e| **e == EventMessages::Ack(RETAILER))
+        .count();
+    assert_eq!(acks, 1);
+}
+
+#[test]
+fn test_engine_round_costs() {
+    let mut settings = engine_test_settings();
+    settings.back_order_cost = BTreeMap::from([(1, 4)]);
+    settings.transport_cost = BTreeMap::from([(1, 2)]);
+    settings.additional_cost = BTreeMap::from([(1, 3)]);
+    let mut engine = start_test_engine_with(settings);
+
+    engine.end_player_round(RETAILER, test_order(6)).unwrap();
+
+    let retailer = engine_user_state(&engine, RETAILER);
+    assert_eq!(
+        retailer.round_costs,
+        RoundCosts {
+            order_cost: 6,
+            magazine_cost: 2,
+            back_order_cost: 2 * 4,
+            transport_cost: 6 * 2,
+            additional_cost: 3,
+            interest_cost: 0,
+            production_cost: 0,
+            surcharge_cost: 0,
+        }
+    );
+    assert_eq!(retailer.money, 100 - 31);
+    assert_eq!(retailer.spent_money, 31);
+
+    // classes without configured costs are not charged for them
+    engine.end_player_round(FACTORY, test_order(4)).unwrap();
+    let factory = engine_user_state(&engine, FACTORY);
+    assert_eq!(factory.round_costs.transport_cost, 0);
+    assert_eq!(factory.round_costs.additional_cost, 0);
+}
+
+#[test]
+fn test_engine_performance_metrics() {
+    let mut engine = start_test_engine();
+    engine.end_player_round(RETAILER, test_order(6)).unwrap();
+    engine.end_player_round(FACTORY, test_order(4)).unwrap();
+
+    // 6 of 8 requested units were shipped
+    assert_eq!(engine_user_state(&engine, RETAILER).performance, 75);
+    assert_eq!(engine_user_state(&engine, FACTORY).performance, 100);
+
+    let mut settings = engine_test_settings();
+    settings.performance_metric = PerformanceMetric::ServiceLevel;
+    let mut engine = start_test_engine_with(settings);
+    engine.end_player_round(RETAILER, test_order(6)).unwrap();
+    assert_eq!(engine_user_state(&engine, RETAILER).performance, 0);
+
+    let mut settings = engine_test_settings();
+    settings.performance_metric = PerformanceMetric::CostPerUnit;
+    let mut engine = start_test_engine_with(settings);
+    engine.end_player_round(RETAILER, test_order(4)).unwrap();
+    // 4 for the order and 2 for storage over 6 shipped units
+    assert_eq!(engine_user_state(&engine, RETAILER).performance, 1);
+}
+
+#[test]
+fn test_engine_delays() {
+    let mut settings = engine_test_settings();
+    settings.incoming_start_queue = BTreeMap::new();
+    settings.requested_start_queue = BTreeMap::new();
+    settings.shipping_delay = BTreeMap::from([(0, 2), (1, 2)]);
+    settings.order_delay = BTreeMap::from([(0, 2), (1, 2)]);
+    let mut engine = start_test_engine_with(settings.clone());
+
+    let retailer = engine_user_state(&engine, RETAILER);
+    assert_eq!(retailer.stock.incoming_orders.len(), 2);
+    assert_eq!(retailer.stock.requested_orders.len(), 2);
+    assert_eq!(retailer.stock.incoming_orders[0].value, 4);
+
+    engine.end_player_round(FACTORY, test_order(9)).unwrap();
+    engine.end_player_round(RETAILER, test_order(7)).unwrap();
+    engine.finish_round(&GameEvents::new(), None).unwrap();
+
+    // new orders wait behind the remaining starting ones
+    let factory = engine_user_state(&engine, FACTORY);
+    let requested: Vec<i64> = factory
+        .stock
+        .requested_orders
+        .iter()
+        .map(|o| o.value)
+        .collect();
+    assert_eq!(requested, vec![4, 7]);
+    let incoming: Vec<i64> = factory
+        .stock
+        .incoming_orders
+        .iter()
+        .map(|o| o.value)
+        .collect();
+    assert_eq!(incoming, vec![4, 9]);
+
+    settings.incoming_start_queue = BTreeMap::from([(0, vec![4]), (1, vec![4, 4])]);
+    let players_classes = BTreeMap::from([(FACTORY, 0), (RETAILER, 1)]);
+    assert!(GameEngine::start_game(settings, &[FACTORY, RETAILER], players_classes, None).is_err());
+}
+
+#[test]
+fn test_engine_rejects_bad_orders() {
+    let mut engine = start_test_engine();
+
+    engine.end_player_round(RETAILER, test_order(101)).unwrap();
+    assert_eq!(engine_user_state(&engine, RETAILER).money, 100);
+    assert!(!engine.round_finished());
+
+    engine.end_player_round(RETAILER, test_order(1)).unwrap();
+    assert!(engine.end_player_round(RETAILER, test_order(1)).is_err());
+
+    let (round_state, events) = engine.finish();
+    assert_eq!(round_state.players_finished, 1);
+    assert!(events.contains(&EventMessages::Bankruptcy(
+        RETAILER,
+        BankruptcyOutcome::OrderRejected
+    )));
+}
+
+#[test]
+fn test_engine_unlimited_money() {
+    let mut settings = engine_test_settings();
+    settings.unlimited_money = true;
+    let mut engine = start_test_engine_with(settings);
+
+    engine.end_player_round(RETAILER, test_order(150)).unwrap();
+
+    let retailer = engine_user_state(&engine, RETAILER);
+    assert_eq!(retailer.money, 100 - 150 - 2);
+    assert_eq!(retailer.round_costs.interest_cost, 0);
+    assert!(!retailer.eliminated);
+}
+
+#[test]
+fn test_engine_negative_balance_interest() {
+    let mut settings = engine_test_settings();
+    settings.bankruptcy_policy = BankruptcyPolicy::NegativeBalance { interest_rate: 10 };
+    let mut engine = start_test_engine_with(settings);
+
+    engine.end_player_round(RETAILER, test_order(148)).unwrap();
+
+    let retailer = engine_user_state(&engine, RETAILER);
+    // 100 - 148 - 2 of storage leaves a debt of 50
+    assert_eq!(retailer.round_costs.interest_cost, 5);
+    assert_eq!(retailer.money, -55);
+
+    let (_, events) = engine.finish();
+    assert!(events.contains(&EventMessages::Bankruptcy(
+        RETAILER,
+        BankruptcyOutcome::NegativeBalance
+    )));
+}
+
+#[test]
+fn test_engine_eliminated_player_stand_in() {
+    let mut settings = engine_test_settings();
+    settings.bankruptcy_policy = BankruptcyPolicy::Eliminate;
+    let mut engine = start_test_engine_with(settings);
+
+    engine.end_player_round(FACTORY, test_order(4)).unwrap();
+    engine.end_player_round(RETAILER, test_order(150)).unwrap();
+    assert!(engine_user_state(&engine, RETAILER).eliminated);
+
+    engine.finish_round(&GameEvents::new(), None).unwrap();
+
+    let (round_state, events) = engine.finish();
+    assert!(events.contains(&EventMessages::Bankruptcy(
+        RETAILER,
+        BankruptcyOutcome::Eliminated
+    )));
+    // the stand-in already ordered what the customer asked for
+    assert_eq!(round_state.players_finished, 1);
+    let stand_in_order = round_state.round_orders.get(&RETAILER).unwrap();
+    assert_eq!(stand_in_order.value, 5);
+    assert_eq!(stand_in_order.sender, FACTORY);
+}
+
+#[test]
+fn test_engine_finish_round() {
+    let mut engine = start_test_engine();
+    engine.end_player_round(FACTORY, test_order(7)).unwrap();
+    engine.end_player_round(RETAILER, test_order(6)).unwrap();
+
+    engine.finish_round(&GameEvents::new(), None).unwrap();
+
+    let finished_round = engine.finished_round().unwrap();
+    assert_eq!(finished_round.round, 1);
+    assert_eq!(
+        finished_round.round_orders.get(&Uuid::nil()).unwrap().value,
+        5
+    );
+
+    let (round_state, events) = engine.finish();
+    assert_eq!(round_state.round, 1);
+    assert_eq!(round_state.demand, 5);
+    assert_eq!(round_state.players_finished, 0);
+    assert!(round_state.round_orders.is_empty());
+
+    let retailer = round_state.users_states.get(&RETAILER).unwrap();
+    assert_eq!(retailer.stock.requested_orders.last().unwrap().value, 5);
+    // factory shipped its starting request of 4, the new order of 6 waits in its queue
+    assert_eq!(retailer.stock.incoming_orders.last().unwrap().value, 4);
+
+    let factory = round_state.users_states.get(&FACTORY).unwrap();
+    assert_eq!(factory.stock.requested_orders.last().unwrap().value, 6);
+    // default supply style starts at 10 and grows, the whole order is delivered
+    assert_eq!(factory.stock.incoming_orders.last().unwrap().value, 7);
+
+    assert!(events.contains(&EventMessages::RoundEnd));
+    assert!(matches!(events.last(), Some(EventMessages::RoundStart(_))));
+}
+
+fn link(sender: Uuid, recipient: Uuid, weight: i64) -> FlowLink {
+    FlowLink {
+        sender,
+        recipient,
+        weight,
+    }
+}
+
+fn start_network_engine(
+    settings: Settings,
+    players_classes: BTreeMap<Uuid, u32>,
+    flow: Flow,
+) -> GameEngine {
+    let players: Vec<Uuid> = players_classes.keys().copied().collect();
+    let engine = GameEngine::start_game(settings, &players, players_classes, Some(flow)).unwrap();
+    let (round_state, _) = engine.finish();
+    GameEngine::new(round_state)
+}
+
+#[test]
+fn test_engine_distribution_network() {
+    let mut settings = engine_test_settings();
+    settings.start_magazine = BTreeMap::from([(0, 2), (1, 2)]);
+    let flow = Flow {
+        links: vec![link(FACTORY, RETAILER, 1), link(FACTORY, SECOND, 1)],
+        split: OrderSplit::Equal,
+    };
+    let players_classes = BTreeMap::from([(FACTORY, 0), (RETAILER, 1), (SECOND, 1)]);
+    let mut engine = start_network_engine(settings, players_classes, flow);
+
+    let factory = engine_user_state(&engine, FACTORY);
+    assert_eq!(factory.stock.requested_orders.len(), 2);
+
+    // 2 in stock + 4 delivered for two requests of 4, split evenly
+    engine.end_player_round(FACTORY, test_order(8)).unwrap();
+    let factory = engine_user_state(&engine, FACTORY);
+    assert_eq!(factory.stock.magazine_state, 0);
+    assert_eq!(factory.stock.back_order_sum, 2);
+    assert_eq!(factory.stock.back_orders.get(&RETAILER), Some(&1));
+    assert_eq!(factory.stock.back_orders.get(&SECOND), Some(&1));
+    assert_eq!(factory.stock.sent_orders[0].recipient, Uuid::nil());
+    assert_eq!(factory.stock.sent_orders[0].value, 6);
+
+    let second = engine_user_state(&engine, SECOND);
+    assert_eq!(second.stock.incoming_orders.last().unwrap().value, 3);
+
+    engine.end_player_round(RETAILER, test_order(4)).unwrap();
+    engine.end_player_round(SECOND, test_order(2)).unwrap();
+    let factory = engine_user_state(&engine, FACTORY);
+    let requested: Vec<(Uuid, i64)> = factory
+        .stock
+        .requested_orders
+        .iter()
+        .map(|o| (o.recipient, o.value))
+        .collect();
+    assert_eq!(requested, vec![(RETAILER, 4), (SECOND, 2)]);
+
+    engine.finish_round(&GameEvents::new(), None).unwrap();
+    let (round_state, _) = engine.finish();
+    for retailer in [RETAILER, SECOND] {
+        let retailer = round_state.users_states.get(&retailer).unwrap();
+        assert_eq!(retailer.stock.requested_orders.last().unwrap().value, 5);
+    }
+    let factory = round_state.users_states.get(&FACTORY).unwrap();
+    assert_eq!(factory.stock.incoming_orders.last().unwrap().value, 8);
+}
+
+#[test]
+fn test_engine_multi_sourcing() {
+    let flow = Flow {
+        links: vec![link(FACTORY, RETAILER, 3), link(SECOND, RETAILER, 1)],
+        split: OrderSplit::Weighted,
+    };
+    let players_classes = BTreeMap::from([(FACTORY, 0), (RETAILER, 1), (SECOND, 0)]);
+    let mut engine = start_network_engine(engine_test_settings(), players_classes, flow);
+
+    // one starting delivery of 4 from each supplier
+    engine.end_player_round(RETAILER, test_order(8)).unwrap();
+    let retailer = engine_user_state(&engine, RETAILER);
+    assert_eq!(retailer.stock.received_order.value, 8);
+    assert_eq!(retailer.stock.received_order.sender, Uuid::nil());
+
+    let factory = engine_user_state(&engine, FACTORY);
+    assert_eq!(factory.stock.requested_orders.last().unwrap().value, 6);
+    let second = engine_user_state(&engine, SECOND);
+    assert_eq!(second.stock.requested_orders.last().unwrap().value, 2);
+}
+
+#[test]
+fn test_engine_rejects_bad_flow() {
+    let players_classes = BTreeMap::from([(FACTORY, 0), (RETAILER, 1), (SECOND, 1)]);
+    let bad_flows = [
+        vec![link(FACTORY, RETAILER, 1), link(RETAILER, FACTORY, 1)],
+        vec![link(FACTORY, Uuid::from_u128(9), 1)],
+        vec![link(FACTORY, FACTORY, 1)],
+        vec![link(FACTORY, RETAILER, 0)],
+    ];
+
+    for links in bad_flows {
+        let flow = Flow {
+            links,
+            split: OrderSplit::Equal,
+        };
+        assert!(GameEngine::start_game(
+            engine_test_settings(),
+            &[FACTORY, RETAILER, SECOND],
+            players_classes.clone(),
+            Some(flow),
+        )
+        .is_err());
+    }
+}
+
+#[test]
+fn test_chain_flow_compatibility() {
+    let stored = serde_json::json!({
+        "first_player": FACTORY,
+        "last_player": RETAILER,
+        "flow": { FACTORY.to_string(): RETAILER, RETAILER.to_string(): Uuid::nil() },
+    });
+
+    let flow: Flow = serde_json::from_value(stored).unwrap();
+    assert_eq!(flow, Flow::chain(&[FACTORY, RETAILER]));
+}
+
+#[test]
+fn test_order_players() {
+    let players = vec![RETAILER, SECOND, FACTORY];
+    let players_classes = BTreeMap::from([(FACTORY, 0), (RETAILER, 1), (SECOND, 2)]);
+    let mut rng = StdRng::seed_from_u64(7);
+
+    let ordered = order_players(
+        players.clone(),
+        &PlayerOrder::Lobby,
+        &players_classes,
+        &mut rng,
+    )
+    .unwrap();
+    assert_eq!(ordered, players);
+
+    let explicit = PlayerOrder::Explicit {
+        players: vec![FACTORY, SECOND, RETAILER],
+    };
+    let ordered = order_players(players.clone(), &explicit, &players_classes, &mut rng).unwrap();
+    assert_eq!(ordered, vec![FACTORY, SECOND, RETAILER]);
+
+    let by_class = PlayerOrder::ByClass {
+        classes: vec![0, 2, 1],
+    };
+    let ordered = order_players(players.clone(), &by_class, &players_classes, &mut rng).unwrap();
+    assert_eq!(ordered, vec![FACTORY, SECOND, RETAILER]);
+
+    let mut shuffled = order_players(
+        players.clone(),
+        &PlayerOrder::Shuffle,
+        &players_classes,
+        &mut rng,
+    )
+    .unwrap();
+    shuffled.sort();
+    assert_eq!(shuffled, vec![FACTORY, RETAILER, SECOND]);
+}
+
+#[test]
+fn test_order_players_errors() {
+    let players = vec![RETAILER, FACTORY];
+    let players_classes = BTreeMap::from([(FACTORY, 0), (RETAILER, 1)]);
+    let mut rng = StdRng::seed_from_u64(7);
+
+    let bad_orders = [
+        PlayerOrder::Explicit {
+            players: vec![FACTORY],
+        },
+        PlayerOrder::Explicit {
+            players: vec![FACTORY, FACTORY],
+        },
+        PlayerOrder::Explicit {
+            players: vec![FACTORY, SECOND],
+        },
+        PlayerOrder::ByClass { classes: vec![0] },
+    ];
+    for order in bad_orders {
+        assert!(order_players(players.clone(), &order, &players_classes, &mut rng).is_err());
+    }
+
+    let missing_class = BTreeMap::from([(FACTORY, 0)]);
+    assert!(order_players(players, &PlayerOrder::Lobby, &missing_class, &mut rng).is_err());
+}
+
+fn team_setup(name: &str, players: Vec<Uuid>) -> TeamSetup {
+    TeamSetup {
+        name: name.to_string(),
+        players,
+        flow: None,
+        order: PlayerOrder::Lobby,
+    }
+}
+
+#[test]
+fn test_engine_start_teams() {
+    let fourth = Uuid::from_u128(4);
+    let players = vec![FACTORY, RETAILER, SECOND, fourth];
+    let start = StartGame {
+        player_classes: BTreeMap::from([(FACTORY, 0), (RETAILER, 1), (SECOND, 0), (fourth, 1)]),
+        flow: None,
+        order: PlayerOrder::Lobby,
+        bots: BTreeMap::new(),
+        seed: None,
+        teams: vec![
+            team_setup("a", vec![FACTORY, RETAILER]),
+            team_setup("b", vec![SECOND, fourth]),
+        ],
+    };
+    let mut rng = StdRng::seed_from_u64(7);
+
+    let engines =
+        GameEngine::start_teams(&engine_test_settings(), players, start, &mut rng).unwrap();
+    assert_eq!(engines.len(), 2);
+
+    let states: Vec<_> = engines.into_iter().map(|e| e.finish()).collect();
+    let (team_a, events_a) = &states[0];
+    let (team_b, _) = &states[1];
+    assert_eq!(team_a.team.name, "a");
+    assert_ne!(team_a.team.id, team_b.team.id);
+    assert_eq!(team_a.flow, Flow::chain(&[FACTORY, RETAILER]));
+    assert_eq!(team_b.flow, Flow::chain(&[SECOND, fourth]));
+    assert_eq!(team_b.players, 2);
+    assert!(team_b.users_states.contains_key(&SECOND));
+    assert!(!team_b.users_states.contains_key(&FACTORY));
+
+    match events_a.as_slice() {
+        [EventMessages::GameStart(update)] => assert_eq!(update.team, team_a.team),
+        _ => panic!("expected a game start"),
+    }
+}
+
+#[test]
+fn test_engine_start_teams_errors() {
+    let players = vec![FACTORY, RETAILER, SECOND];
+    let player_classes = BTreeMap::from([(FACTORY, 0), (RETAILER, 1), (SECOND, 1)]);
+    let mut rng = StdRng::seed_from_u64(7);
+
+    let bad_teams = [
+        vec![team_setup("a", vec![FACTORY, RETAILER])],
+        vec![
+            team_setup("a", vec![FACTORY, RETAILER]),
+            team_setup("b", vec![SECOND, RETAILER]),
+        ],
+        vec![
+            team_setup("a", vec![FACTORY, RETAILER]),
+            team_setup("a", vec![SECOND]),
+        ],
+    ];
+
+    for teams in bad_teams {
+        let start = StartGame {
+            player_classes: player_classes.clone(),
+            flow: None,
+            order: PlayerOrder::Lobby,
+            teams,
+            bots: BTreeMap::new(),
+            seed: None,
+        };
+        assert!(
+            GameEngine::start_teams(&engine_test_settings(), players.clone(), start, &mut rng)
+                .is_err()
+        );
+    }
+}
+
+#[test]
+fn test_engine_round_deadline() {
+    let mut settings = engine_test_settings();
+    settings.round_time_limit = Some(30);
+    settings.default_order = DefaultOrderPolicy::Fixed { value: 3 };
+    let players_classes = BTreeMap::from([(FACTORY, 0), (RETAILER, 1)]);
+    let engine =
+        GameEngine::start_game(settings, &[FACTORY, RETAILER], players_classes, None).unwrap();
+    let (round_state, events) = engine.finish();
+    assert!(events.contains(&EventMessages::RoundDeadline(0, 30)));
+
+    let mut engine = GameEngine::new(round_state);
+    engine.end_player_round(RETAILER, test_order(6)).unwrap();
+    engine.submit_missing_orders().unwrap();
+    assert!(engine.round_finished());
+    assert_eq!(
+        engine_user_state(&engine, FACTORY).stock.placed_order.value,
+        3
+    );
+    assert_eq!(
+        engine_user_state(&engine, RETAILER)
+            .stock
+            .placed_order
+            .value,
+        6
+    );
+
+    engine.finish_round(&GameEvents::new(), None).unwrap();
+    let (_, events) = engine.finish();
+    assert!(events.contains(&EventMessages::RoundTimeout(vec![FACTORY])));
+    assert!(events.contains(&EventMessages::RoundDeadline(1, 30)));
+}
+
+#[test]
+fn test_engine_default_orders() {
+    let policies = [
+        (DefaultOrderPolicy::Zero, 0),
+        (DefaultOrderPolicy::PassThrough, 8),
+        // the factory can't pay for it, nothing is ordered
+        (DefaultOrderPolicy::Fixed { value: 1000 }, 0),
+    ];
+
+    for (policy, expected) in policies {
+        let mut settings = engine_test_settings();
+        settings.default_order = policy;
+        let mut engine = start_test_engine_with(settings);
+
+        engine.submit_missing_orders().unwrap();
+        assert!(engine.round_finished());
+        assert_eq!(
+            engine_user_state(&engine, RETAILER)
+                .stock
+                .placed_order
+                .value,
+            expected
+        );
+    }
+
+    let mut engine = start_test_engine();
+    engine.end_player_round(FACTORY, test_order(5)).unwrap();
+    engine.end_player_round(RETAILER, test_order(6)).unwrap();
+    engine.finish_round(&GameEvents::new(), None).unwrap();
+    engine.submit_missing_orders().unwrap();
+    assert_eq!(
+        engine_user_state(&engine, FACTORY).stock.placed_order.value,
+        5
+    );
+}
+
+fn bot_start(players_classes: BTreeMap<Uuid, u32>, bots: BTreeMap<Uuid, BotSetup>) -> StartGame {
+    StartGame {
+        player_classes: players_classes,
+        flow: None,
+        order: PlayerOrder::Lobby,
+        teams: Vec::new(),
+        bots,
+        seed: None,
+    }
+}
+
+fn bot_setup(class: Option<u32>, policy: BotPolicy) -> BotSetup {
+    BotSetup { class, policy }
+}
+
+#[test]
+fn test_bot_policies() {
+    let engine = start_test_engine();
+    let retailer = engine_user_state(&engine, RETAILER);
+    // 2 in stock, 4 on the way and 8 requested
+    let view = BotView {
+        stock: &retailer.stock,
+        requested: 8,
+    };
+    let bot = |policy| Bot {
+        policy,
+        expected_demand: 4,
+    };
+    let mut rng = round_rng(7, 1, 0);
+
+    assert_eq!(
+        bot_order(&mut bot(BotPolicy::PassThrough), &view, &mut rng),
+        8
+    );
+    assert_eq!(
+        bot_order(
+            &mut bot(BotPolicy::BaseStock { target: 10 }),
+            &view,
+            &mut rng
+        ),
+        12
+    );
+
+    let mut sterman = bot(BotPolicy::AnchorAndAdjust {
+        smoothing: 50,
+        target_stock: 10,
+        stock_adjustment: 50,
+        supply_line_weight: 100,
+    });
+    // forecast 4 + (8 - 4) / 2 = 6, adjusted by (10 - (-6) - 4) / 2 = 6
+    assert_eq!(bot_order(&mut sterman, &view, &mut rng), 12);
+    assert_eq!(sterman.expected_demand, 6);
+
+    let random = BotPolicy::Random { min: 2, max: 5 };
+    for _ in 0..20 {
+        let value = bot_order(&mut bot(random.clone()), &view, &mut rng);
+        assert!((2..=5).contains(&value));
+    }
+    // the same seed and round give the same orders
+    let orders = |rng: &mut ChaCha8Rng| -> Vec<i64> {
+        (0..20)
+            .map(|_| bot_order(&mut bot(random.clone()), &view, rng))
+            .collect()
+    };
+    assert_eq!(
+        orders(&mut round_rng(7, 3, 0)),
+        orders(&mut round_rng(7, 3, 0))
+    );
+}
+
+#[test]
+fn test_engine_bots_play_whole_game() {
+    let players_classes = BTreeMap::from([(FACTORY, 0), (RETAILER, 1)]);
+    let bots = BTreeMap::from([
+        (FACTORY, bot_setup(None, BotPolicy::PassThrough)),
+        (
+            RETAILER,
+            bot_setup(None, BotPolicy::BaseStock { target: 12 }),
+        ),
+    ]);
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut engines = GameEngine::start_teams(
+        &engine_test_settings(),
+        vec![FACTORY, RETAILER],
+        bot_start(players_classes, bots),
+        &mut rng,
+    )
+    .unwrap();
+    let mut engine = engines.remove(0);
+
+    let mut rounds = 0;
+    while engine.round_finished() && !engine.game_finished() {
+        engine.finish_round(&GameEvents::new(), None).unwrap();
+        rounds += 1;
+    }
+
+    assert!(engine.game_finished());
+    assert_eq!(rounds, 10);
+}
+
+#[test]
+fn test_engine_rewind_bots_to_first_round() {
+    let players_classes = BTreeMap::from([(FACTORY, 0)]);
+    let bots = BTreeMap::from([(RETAILER, bot_setup(Some(1), BotPolicy::PassThrough))]);
+    let mut rng = StdRng::seed_from_u64(7);
+    let engine = GameEngine::start_teams(
+        &engine_test_settings(),
+        vec![FACTORY],
+        bot_start(players_classes, bots),
+        &mut rng,
+    )
+    .unwrap()
+    .remove(0);
+
+    // the snapshot of the first round is taken before the bot ordered
+    let snapshot = engine.finished_round().unwrap().clone();
+    assert!(snapshot.round_orders.is_empty());
+
+    let mut rewound = GameEngine::new(snapshot);
+    rewound.rewind(&GameEvents::new(), None).unwrap();
+    let (round_state, _) = rewound.finish();
+    let (started, _) = engine.finish();
+    assert_eq!(round_state.users_states, started.users_states);
+    assert_eq!(round_state.round_orders, started.round_orders);
+    assert_eq!(round_state.players_finished, 1);
+}
+
+#[test]
+fn test_engine_bot_joins_and_takes_over() {
+    let players_classes = BTreeMap::from([(FACTORY, 0)]);
+    let bots = BTreeMap::from([(RETAILER, bot_setup(Some(1), BotPolicy::PassThrough))]);
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut engines = GameEngine::start_teams(
+        &engine_test_settings(),
+        vec![FACTORY],
+        bot_start(players_classes.clone(), bots),
+        &mut rng,
+    )
+    .unwrap();
+    let mut engine = engines.remove(0);
+
+    assert_eq!(
+        engine_user_state(&engine, RETAILER)
+            .stock
+            .placed_order
+            .value,
+        8
+    );
+    assert!(!engine.round_finished());
+
+    engine
+        .set_bot(FACTORY, Some(BotPolicy::Random { min: 1, max: 1 }))
+        .unwrap();
+    assert!(engine.round_finished());
+    assert_eq!(
+        engine_user_state(&engine, FACTORY).stock.placed_order.value,
+        1
+    );
+
+    let bots = BTreeMap::from([(RETAILER, bot_setup(None, BotPolicy::PassThrough))]);
+    assert!(GameEngine::start_teams(
+        &engine_test_settings(),
+        vec![FACTORY],
+        bot_start(players_classes, bots),
+        &mut rng,
+    )
+    .is_err());
+}
+
+fn demand_sequence(style: &GeneratedOrderStyle, seed: u64) -> Vec<i64> {
+    let mut demand = start_value(style).unwrap();
+    (1..=20)
+        .map(|round| {
+            demand = generate_demand(demand, style, round, &mut round_rng(seed, round, 0));
+            demand
+        })
+        .collect()
+}
+
+#[test]
+fn test_random_demand() {
+    let styles = [
+        GeneratedOrderStyle::Normal {
+            mean: 10,
+            std_dev: 3,
+        },
+        GeneratedOrderStyle::Poisson { mean: 8 },
+        GeneratedOrderStyle::Uniform { min: 4, max: 12 },
+        GeneratedOrderStyle::StepChange {
+            before: 4,
+            after: 40,
+            round: 10,
+            noise: 1,
+        },
+    ];
+
+    for style in &styles {
+        let sequence = demand_sequence(style, 42);
+        assert_eq!(sequence, demand_sequence(style, 42));
+        assert_ne!(sequence, demand_sequence(style, 43));
+        assert!(sequence.iter().all(|d| *d >= 0));
+    }
+
+    let uniform = demand_sequence(&styles[2], 1);
+    assert!(uniform.iter().all(|d| (4..=12).contains(d)));
+    let step = demand_sequence(&styles[3], 1);
+    assert!(step[..8].iter().all(|d| *d < 20));
+    assert!(step[9..].iter().all(|d| *d > 20));
+
+    let bad_styles = [
+        GeneratedOrderStyle::Normal {
+            mean: 10,
+            std_dev: -1,
+        },
+        GeneratedOrderStyle::Poisson { mean: 0 },
+        GeneratedOrderStyle::Uniform { min: 5, max: 4 },
+    ];
+    for style in &bad_styles {
+        assert!(start_value(style).is_err());
+    }
+}
+
+#[test]
+fn test_engine_teams_share_random_demand() {
+    let fourth = Uuid::from_u128(4);
+    let players = vec![FACTORY, RETAILER, SECOND, fourth];
+    let mut settings = engine_test_settings();
+    settings.demand_style = GeneratedOrderStyle::Poisson { mean: 6 };
+    let start = StartGame {
+        player_classes: BTreeMap::from([(FACTORY, 0), (RETAILER, 1), (SECOND, 0), (fourth, 1)]),
+        flow: None,
+        order: PlayerOrder::Lobby,
+        bots: BTreeMap::new(),
+        seed: Some(42),
+        teams: vec![
+            team_setup("a", vec![FACTORY, RETAILER]),
+            team_setup("b", vec![SECOND, fourth]),
+        ],
+    };
+    let mut rng = StdRng::seed_from_u64(7);
+    let engines = GameEngine::start_teams(&settings, players, start, &mut rng).unwrap();
+
+    let demands: Vec<Vec<i64>> = engines
+        .into_iter()
+        .map(|mut engine| {
+            (0..5)
+                .map(|_| {
+                    let (round_state, events) = engine.clone().finish();
+                    assert_eq!(round_state.seed, 42);
+                    // the owner gets the seed with every update
+                    assert!(events.iter().any(|e| matches!(e,
+                        EventMessages::GameStart(u) | EventMessages::RoundStart(u)
+                            if u.seed == Some(42))));
+                    for player in round_state.users_states.keys() {
+                        engine.end_player_round(*player, test_order(1)).unwrap();
+                    }
+                    engine.finish_round(&GameEvents::new(), None).unwrap();
+                    let (round_state, _) = engine.clone().finish();
+                    round_state.demand
+                })
+                .collect()
+        })
+        .collect();
+
+    assert_eq!(demands[0], demands[1]);
+    assert_ne!(demands[0], vec![demands[0][0]; 5]);
+}
+
+#[test]
+fn test_demand_patterns() {
+    let seasonal = GeneratedOrderStyle::Seasonal {
+        base: 10,
+        amplitude: 5,
+        period: 4,
+    };
+    assert_eq!(start_value(&seasonal).unwrap(), 10);
+    assert_eq!(demand_sequence(&seasonal, 0)[..5], [15, 10, 5, 10, 15]);
+
+    let promotion = GeneratedOrderStyle::Promotion {
+        base: 4,
+        promotions: vec![
+            DemandPromotion {
+                from: 2,
+                to: 3,
+                increase: 6,
+            },
+            DemandPromotion {
+                from: 3,
+                to: 4,
+                increase: 2,
+            },
+        ],
+    };
+    assert_eq!(start_value(&promotion).unwrap(), 4);
+    assert_eq!(demand_sequence(&promotion, 0)[..5], [4, 10, 12, 6, 4]);
+
+    let segment = |from, to, value| DemandSegment { from, to, value };
+    let piecewise = GeneratedOrderStyle::Piecewise {
+        segments: vec![segment(0, 1, 4), segment(2, 3, 8)],
+    };
+    assert_eq!(start_value(&piecewise).unwrap(), 4);
+    assert_eq!(demand_sequence(&piecewise, 0)[..5], [4, 8, 8, 8, 8]);
+
+    let bad_styles = [
+        GeneratedOrderStyle::Seasonal {
+            base: 10,
+            amplitude: 5,
+            period: 0,
+        },
+        GeneratedOrderStyle::Seasonal {
+            base: 4,
+            amplitude: 5,
+            period: 4,
+        },
+        GeneratedOrderStyle::Promotion {
+            base: 4,
+            promotions: vec![DemandPromotion {
+                from: 3,
+                to: 2,
+                increase: 1,
+            }],
+        },
+        GeneratedOrderStyle::Piecewise {
+            segments: Vec::new(),
+        },
+        GeneratedOrderStyle::Piecewise {
+            segments: vec![segment(1, 2, 4)],
+        },
+        GeneratedOrderStyle::Piecewise {
+            segments: vec![segment(0, 1, 4), segment(3, 4, 8)],
+        },
+        GeneratedOrderStyle::Piecewise {
+            segments: vec![segment(0, 3, 4), segment(2, 4, 8)],
+        },
+    ];
+    for style in &bad_styles {
+        assert!(start_value(style).is_err());
+    }
+}
+
+#[test]
+fn test_demand_sequences() {
+    let list = |after_end| GeneratedOrderStyle::List {
+        list: vec![4, 4, 4, 8, 8],
+        after_end,
+    };
+    let cases = [
+        (GeneratedOrderStyle::Default, vec![10, 15, 22, 33, 49, 73]),
+        (
+            GeneratedOrderStyle::Linear {
+                start: 4,
+                increase: 2,
+            },
+            vec![4, 6, 8, 10, 12, 14],
+        ),
+        (
+            GeneratedOrderStyle::Multiplication {
+                start: 1,
+                increase: 3,
+            },
+            vec![1, 3, 9, 27, 81, 243],
+        ),
+        (
+            GeneratedOrderStyle::Exponential {
+                start: 2,
+                power: 1,
+                modulator: 1,
+            },
+            vec![2, 4, 8, 16, 32, 64],
+        ),
+        (list(ListEnd::RepeatLast), vec![4, 4, 4, 8, 8, 8, 8]),
+        (list(ListEnd::Cycle), vec![4, 4, 4, 8, 8, 4, 4]),
+        (list(ListEnd::EndGame), vec![4, 4, 4, 8, 8, 8, 8]),
+        (
+            GeneratedOrderStyle::Normal {
+                mean: 6,
+                std_dev: 0,
+            },
+            vec![6, 6, 6],
+        ),
+        (
+            GeneratedOrderStyle::Uniform { min: 3, max: 3 },
+            vec![3, 3, 3],
+        ),
+        (
+            GeneratedOrderStyle::StepChange {
+                before: 4,
+                after: 8,
+                round: 2,
+                noise: 0,
+            },
+            vec![4, 4, 8, 8],
+        ),
+    ];
+
+    for (style, expected) in cases {
+        let mut sequence = vec![start_value(&style).unwrap()];
+        sequence.extend(demand_sequence(&style, 0));
+        assert_eq!(sequence[..expected.len()], expected, "{:?}", style);
+    }
+}
+
+#[test]
+fn test_engine_list_demand_ends_game() {
+    let mut settings = engine_test_settings();
+    settings.demand_style = GeneratedOrderStyle::List {
+        list: vec![4, 4, 8],
+        after_end: ListEnd::EndGame,
+    };
+    let mut engine = start_test_engine_with(settings);
+
+    let mut demands = Vec::new();
+    while !engine.game_finished() {
+        engine.end_player_round(FACTORY, test_order(1)).unwrap();
+        engine.end_player_round(RETAILER, test_order(1)).unwrap();
+        engine.finish_round(&GameEvents::new(), None).unwrap();
+        demands.push(engine.clone().finish().0.demand);
+    }
+
+    // the settings allow 10 rounds, the schedule only has 3
+    assert_eq!(engine.finish().0.round, 3);
+    assert_eq!(demands[..2], [4, 8]);
+}
+
+#[test]
+fn test_engine_product_demand_ends_game() {
+    let mut settings = engine_test_settings();
+    settings.products = vec![Product {
+        name: "ice".to_string(),
+        demand_style: GeneratedOrderStyle::List {
+            list: vec![2, 3],
+            after_end: ListEnd::EndGame,
+        },
+        supply_style: GeneratedOrderStyle::Linear {
+            start: 100,
+            increase: 0,
+        },
+        resource_basic_price: 1,
+        resource_price: BTreeMap::from([(0, 1), (1, 1)]),
+        start_magazine: BTreeMap::from([(0, 5), (1, 3)]),
+        magazine_cost: BTreeMap::from([(0, 2), (1, 2)]),
+    }];
+    let mut engine = start_test_engine_with(settings);
+
+    while !engine.game_finished() {
+        engine.end_player_round(FACTORY, test_order(1)).unwrap();
+        engine.end_player_round(RETAILER, test_order(1)).unwrap();
+        engine.finish_round(&GameEvents::new(), None).unwrap();
+    }
+
+    // the main demand goes on, the ice schedule has 2 rounds
+    assert_eq!(engine.finish().0.round, 2);
+}
+
+#[test]
+fn test_engine_supply_capacity() {
+    let mut settings = engine_test_settings();
+    settings.supply_style = GeneratedOrderStyle::Linear {
+        start: 5,
+        increase: 1,
+    };
+    let flow = Flow {
+        links: vec![link(FACTORY, RETAILER, 1), link(SECOND, RETAILER, 1)],
+        split: OrderSplit::Equal,
+    };
+    let players_classes = BTreeMap::from([(FACTORY, 0), (RETAILER, 1), (SECOND, 0)]);
+    let mut engine = start_network_engine(settings, players_classes, flow);
+
+    // 9 ordered from a capacity of 6, shared in proportion to the orders
+    engine.end_player_round(FACTORY, test_order(6)).unwrap();
+    engine.end_player_round(SECOND, test_order(3)).unwrap();
+    engine.end_player_round(RETAILER, test_order(8)).unwrap();
+    engine.finish_round(&GameEvents::new(), None).unwrap();
+
+    let (round_state, events) = engine.finish();
+    let supply = Supply {
+        capacity: 6,
+        ordered: 9,
+        delivered: 6,
+    };
+    assert_eq!(round_state.supply, supply);
+    assert_eq!(supply.shortage(), 3);
+    let delivered = |player| {
+        let user_state = round_state.users_states.get(&player).unwrap();
+        user_state.stock.incoming_orders.last().unwrap().value
+    };
+    assert_eq!((delivered(FACTORY), delivered(SECOND)), (4, 2));
+    match events.last() {
+        Some(EventMessages::RoundStart(update)) => assert_eq!(update.supply, supply),
+        _ => panic!("expected a round start"),
+    }
+
+    let mut engine = GameEngine::new(round_state);
+    engine.end_player_round(FACTORY, test_order(2)).unwrap();
+    engine.end_player_round(SECOND, test_order(2)).unwrap();
+    engine.end_player_round(RETAILER, test_order(2)).unwrap();
+    engine.finish_round(&GameEvents::new(), None).unwrap();
+    let supply = engine.finish().0.supply;
+    assert_eq!(supply.capacity, 7);
+    assert_eq!(supply.shortage(), 0);
+}
+
+#[test]
+fn test_engine_production() {
+    let mut settings = engine_test_settings();
+    settings.production_capacity = BTreeMap::from([(0, 5)]);
+    settings.production_lead_time = BTreeMap::from([(0, 2)]);
+    settings.production_cost = BTreeMap::from([(0, 1)]);
+    let mut engine = start_test_engine_with(settings);
+    assert_eq!(
+        engine_user_state(&engine, FACTORY).stock.production,
+        vec![4, 4]
+    );
+    assert!(engine_user_state(&engine, RETAILER)
+        .stock
+        .production
+        .is_empty());
+
+    // only 5 of the ordered 8 can be manufactured, the 4 from the starting queue
+    // left the production line
+    engine.end_player_round(FACTORY, test_order(8)).unwrap();
+    engine.end_player_round(RETAILER, test_order(8)).unwrap();
+    let factory = engine_user_state(&engine, FACTORY);
+    assert_eq!(factory.stock.placed_order.value, 5);
+    assert_eq!(factory.stock.placed_order.cost, 5);
+    assert_eq!(factory.round_costs.production_cost, 4);
+    assert_eq!(
+        engine_user_state(&engine, RETAILER)
+            .stock
+            .placed_order
+            .value,
+        8
+    );
+
+    let events = GameEvents {
+        events: vec![GameEvent {
+            name: "breakdown".to_string(),
+            condition: EventCondition::RoundMet {
+                round: 1,
+                compare: None,
+            },
+            actions: vec![EventAction::ChangeProductionCapacity {
+                class: 0,
+                capacity: 2,
+            }],
+            run_once: true,
+            every: None,
+            from_round: None,
+            to_round: None,
+            cooldown: None,
+        }],
+    };
+    engine.finish_round(&events, None).unwrap();
+    let (round_state, messages) = engine.finish();
+    let factory = round_state.users_states.get(&FACTORY).unwrap();
+    assert_eq!(factory.stock.incoming_orders.last().unwrap().value, 4);
+    assert_eq!(factory.stock.production, vec![4, 5]);
+    assert!(messages.contains(&EventMessages::GameEventProductionCapacity(0, 2)));
+
+    let mut engine = GameEngine::new(round_state);
+    engine.end_player_round(FACTORY, test_order(8)).unwrap();
+    assert_eq!(
+        engine_user_state(&engine, FACTORY).stock.placed_order.value,
+        2
+    );
+}
+
+#[test]
+fn test_engine_production_shared_by_products() {
+    let mut settings = engine_test_settings();
+    settings.production_capacity = BTreeMap::from([(0, 5)]);
+    settings.products = vec![Product {
+        name: "ice".to_string(),
+        demand_style: GeneratedOrderStyle::Linear {
+            start: 2,
+            increase: 1,
+        },
+        supply_style: GeneratedOrderStyle::Linear {
+            start: 100,
+            increase: 0,
+        },
+        resource_basic_price: 1,
+        resource_price: BTreeMap::from([(0, 1), (1, 1)]),
+        start_magazine: BTreeMap::from([(0, 5), (1, 3)]),
+        magazine_cost: BTreeMap::from([(0, 2), (1, 2)]),
+    }];
+    let mut engine = start_test_engine_with(settings);
+
+    // 10 ordered in total, the 5 manufactured are split 6 to 4
+    let ice = BTreeMap::from([("ice".to_string(), test_order(4))]);
+    engine
+        .end_player_round_with_products(FACTORY, test_order(6), ice)
+        .unwrap();
+    let factory = engine_user_state(&engine, FACTORY);
+    assert_eq!(factory.stock.placed_order.value, 3);
+    assert_eq!(factory.products["ice"].placed_order.value, 2);
+    assert_eq!(factory.round_costs.order_cost, 5);
+}
+
+#[test]
+fn test_engine_warehouse() {
+    let cases = [
+        // 14 in stock after receiving 4 and shipping 4, 6 fit in the warehouse
+        (WarehouseOverflow::Reject, 0, (6, 4, 0, 0)),
+        (WarehouseOverflow::Reject, 50, (3, 4, 3, 0)),
+        (WarehouseOverflow::Surcharge { cost: 2 }, 0, (10, 0, 0, 8)),
+        (WarehouseOverflow::Surcharge { cost: 2 }, 50, (5, 0, 5, 8)),
+    ];
+
+    for (overflow, spoilage_rate, expected) in cases {
+        let mut settings = engine_test_settings();
+        settings.warehouse_capacity = BTreeMap::from([(0, 6)]);
+        settings.warehouse_overflow = overflow;
+        settings.spoilage_rate = BTreeMap::from([(0, spoilage_rate)]);
+        let mut engine = start_test_engine_with(settings);
+
+        engine.end_player_round(FACTORY, test_order(0)).unwrap();
+        let factory = engine_user_state(&engine, FACTORY);
+        let result = (
+            factory.stock.magazine_state,
+            factory.stock.rejected,
+            factory.stock.spoiled,
+            factory.round_costs.surcharge_cost,
+        );
+        assert_eq!(result, expected);
+    }
+
+    let mut settings = engine_test_settings();
+    settings.spoilage_rate = BTreeMap::from([(0, 101)]);
+    let players_classes = BTreeMap::from([(FACTORY, 0), (RETAILER, 1)]);
+    assert!(GameEngine::start_game(settings, &[FACTORY, RETAILER], players_classes, None).is_err());
+}
+
+#[test]
+fn test_engine_products() {
+    let mut settings = engine_test_settings();
+    settings.products = vec![Product {
+        name: "ice".to_string(),
+        demand_style: GeneratedOrderStyle::Linear {
+            start: 2,
+            increase: 1,
+        },
+        supply_style: GeneratedOrderStyle::Linear {
+            start: 100,
+            increase: 0,
+        },
+        resource_basic_price: 1,
+        resource_price: BTreeMap::from([(0, 1), (1, 1)]),
+        start_magazine: BTreeMap::from([(0, 5), (1, 3)]),
+        magazine_cost: BTreeMap::from([(0, 2), (1, 2)]),
+    }];
+    settings.warehouse_capacity = BTreeMap::from([(1, 2)]);
+    let mut engine = start_test_engine_with(settings);
+
+    let retailer = engine_user_state(&engine, RETAILER);
+    assert_eq!(retailer.products["ice"].magazine_state, 3);
+    assert_eq!(
+        retailer.products["ice"]
+            .requested_orders
+            .last()
+            .unwrap()
+            .value,
+        2
+    );
+
+    let unknown = BTreeMap::from([("tea".to_string(), test_order(1))]);
+    assert!(engine
+        .end_player_round_with_products(RETAILER, test_order(1), unknown)
+        .is_err());
+
+    let ice = BTreeMap::from([("ice".to_string(), test_order(5))]);
+    engine
+        .end_player_round_with_products(RETAILER, test_order(4), ice)
+        .unwrap();
+    let retailer = engine_user_state(&engine, RETAILER);
+    let ice = &retailer.products["ice"];
+    assert_eq!(ice.placed_order.value, 5);
+    // both products share the budget and the warehouse of 2 units
+    assert_eq!(retailer.round_costs.order_cost, 9);
+    assert_eq!(retailer.stock.magazine_state + ice.magazine_state, 2);
+    assert_eq!(retailer.stock.rejected + ice.rejected, 1);
+
+    engine.end_player_round(FACTORY, test_order(0)).unwrap();
+    let factory = engine_user_state(&engine, FACTORY);
+    assert_eq!(
+        factory.products["ice"]
+            .requested_orders
+            .last()
+            .unwrap()
+            .value,
+        5
+    );
+    engine.finish_round(&GameEvents::new(), None).unwrap();
+
+    let (round_state, events) = engine.finish();
+    assert_eq!(round_state.products["ice"].demand, 3);
+    let retailer = round_state.users_states.get(&RETAILER).unwrap();
+    assert_eq!(
+        retailer.products["ice"]
+            .requested_orders
+            .last()
+            .unwrap()
+            .value,
+        3
+    );
+    match events.last() {
+        Some(EventMessages::RoundStart(update)) => {
+            assert_eq!(update.products, round_state.products)
+        }
+        _ => panic!("expected a round start"),
+    }
+}
+
+fn visibility_update(visibility: Visibility) -> GameUpdate {
+    let mut settings = engine_test_settings();
+    settings.visibility = visibility;
+    let flow = Flow {
+        links: vec![link(FACTORY, SECOND, 1), link(SECOND, RETAILER, 1)],
+        split: OrderSplit::Equal,
+    };
+    let players_classes = BTreeMap::from([(FACTORY, 0), (SECOND, 0), (RETAILER, 1)]);
+    let mut engine = start_network_engine(settings, players_classes, flow);
+    for player in [FACTORY, SECOND, RETAILER] {
+        engine.end_player_round(player, test_order(4)).unwrap();
+    }
+    engine.finish_round(&GameEvents::new(), None).unwrap();
+
+    match engine.finish().1.pop() {
+        Some(EventMessages::RoundStart(update)) => update,
+        _ => panic!("expected a round start"),
+    }
+}
+
+#[test]
+fn test_update_visibility() {
+    let seen =
+        |update: &GameUpdate| -> Vec<Uuid> { update.player_states.keys().copied().collect() };
+
+    let full = visibility_update(Visibility::Full);
+    assert_eq!(
+        full.clone().visible_to(SECOND),
+        GameUpdate {
+            seed: None,
+            ..full.clone()
+        }
+    );
+
+    let neighbors = visibility_update(Visibility::Neighbors);
+    let factory = neighbors.clone().visible_to(FACTORY);
+    assert_eq!(seen(&factory), vec![FACTORY, SECOND]);
+    assert_eq!(factory.flow.links, vec![link(FACTORY, SECOND, 1)]);
+    assert!(!factory.round_orders.contains_key(&Uuid::nil()));
+    assert!(factory.send_orders.contains_key(&Uuid::nil()));
+    let second = neighbors.clone().visible_to(SECOND);
+    assert_eq!(seen(&second), vec![FACTORY, RETAILER, SECOND]);
+    // neighbours of the ends see their states, not the customers or the supply
+    assert!(!second.round_orders.contains_key(&Uuid::nil()));
+    assert!(!second.send_orders.contains_key(&Uuid::nil()));
+    let retailer = neighbors.clone().visible_to(RETAILER);
+    assert!(retailer.round_orders.contains_key(&Uuid::nil()));
+
+    let own = visibility_update(Visibility::Own).visible_to(SECOND);
+    assert_eq!(seen(&own), vec![SECOND]);
+    assert_eq!(own.flow.links.len(), 2);
+    assert_eq!(own.round_orders.keys().collect::<Vec<_>>(), vec![&SECOND]);
+    assert_eq!(own.supply, Supply::default());
+
+    let pos = visibility_update(Visibility::PointOfSale);
+    let second = pos.clone().visible_to(SECOND);
+    assert_eq!(seen(&second), vec![SECOND]);
+    assert_eq!(
+        second.round_orders[&Uuid::nil()],
+        pos.round_orders[&Uuid::nil()]
+    );
+
+    // the lobby owner isn't in the chain and sees all of it, with the seed to replay
+    // the game
+    assert!(pos.seed.is_some());
+    assert_eq!(pos.clone().visible_to(Uuid::from_u128(9)), pos);
+}
+
+#[test]
+fn test_game_end_visibility() {
+    let update = visibility_update(Visibility::Neighbors);
+    let game_end = GameEnd {
+        player_states: update.player_states.clone(),
+        stats: HashMap::from([(
+            "Money".to_string(),
+            update
+                .player_states
+                .keys()
+                .map(|p| (*p, vec![1, 2]))
+                .collect(),
+        )]),
+        teams_stats: HashMap::new(),
+    };
+
+    let visible = visible_players(&Visibility::Neighbors, &update.flow, FACTORY).unwrap();
+    assert_eq!(visible, BTreeSet::from([FACTORY, SECOND]));
+    let seen = game_end.clone().visible_to(&visible);
+    assert_eq!(
+        seen.player_states.keys().copied().collect::<Vec<_>>(),
+        vec![FACTORY, SECOND]
+    );
+    assert_eq!(seen.stats["Money"].len(), 2);
+
+    let own = visible_players(&Visibility::Own, &update.flow, SECOND).unwrap();
+    assert_eq!(own, BTreeSet::from([SECOND]));
+    assert_eq!(
+        visible_players(&Visibility::Full, &update.flow, SECOND),
+        None
+    );
+}
+
+fn money_over(value: i64, compare: Option<Comparison>) -> EventCondition {
+    EventCondition::ValueExceed {
+        resource: Resource::Money,
+        met_by: MetBy::SinglePlayer,
+        value,
+        compare,
+    }
+}
+
+fn pop_up_event(name: &str, condition: EventCondition) -> GameEvent {
+    GameEvent {
+        name: name.to_string(),
+        condition,
+        actions: vec![EventAction::ShowMessage {
+            message: name.to_string(),
+            target: ActionTarget::EventTarget,
+        }],
+        run_once: false,
+        every: None,
+        from_round: None,
+        to_round: None,
+        cooldown: None,
+    }
+}
+
+#[test]
+fn test_engine_compound_conditions() {
+    let (mut round_state, _) = start_test_engine().finish();
+    round_state.users_states.get_mut(&FACTORY).unwrap().money = 50;
+    round_state.users_states.get_mut(&RETAILER).unwrap().money = 500;
+    let mut engine = GameEngine::new(round_state);
+    engine.end_player_round(FACTORY, test_order(0)).unwrap();
+    engine.end_player_round(RETAILER, test_order(0)).unwrap();
+
+    let round_from = |round| EventCondition::RoundMet {
+        round,
+        compare: Some(Comparison::GreaterOrEqual),
+    };
+    let events = GameEvents {
+        events: vec![
+            pop_up_event(
+                "and",
+                EventCondition::And {
+                    conditions: vec![round_from(1), money_over(100, None)],
+                },
+            ),
+            pop_up_event(
+                "or",
+                EventCondition::Or {
+                    conditions: vec![
+                        money_over(100, Some(Comparison::Less)),
+                        money_over(1000, None),
+                    ],
+                },
+            ),
+            pop_up_event(
+                "not",
+                EventCondition::Not {
+                    condition: Box::new(money_over(100, None)),
+                },
+            ),
+            pop_up_event(
+                "never",
+                EventCondition::And {
+                    conditions: vec![
+                        round_from(1),
+                        EventCondition::Not {
+                            condition: Box::new(round_from(1)),
+                        },
+                    ],
+                },
+            ),
+            pop_up_event(
+                "all",
+                EventCondition::ValueExceed {
+                    resource: Resource::Money,
+                    met_by: MetBy::AllPlayers,
+                    value: 1000,
+                    compare: Some(Comparison::Less),
+                },
+            ),
+        ],
+    };
+    engine.finish_round(&events, None).unwrap();
+
+    let pop_ups: Vec<(Uuid, String)> = engine
+        .finish()
+        .1
+        .into_iter()
+        .filter_map(|msg| match msg {
+            EventMessages::GameEventPopUpUser(player, message) => Some((player, message)),
+            _ => None,
+        })
+        .collect();
+    let expected = vec![
+        (RETAILER, "and".to_string()),
+        (FACTORY, "or".to_string()),
+        (FACTORY, "not".to_string()),
+        (FACTORY, "all".to_string()),
+        (RETAILER, "all".to_string()),
+    ];
+    assert_eq!(pop_ups, expected);
+
+    let condition: EventCondition =
+        serde_json::from_str(r#"{"type": "RoundMet", "round": 10, "compare": ">="}"#).unwrap();
+    assert_eq!(condition, round_from(10));
+}
+
+fn group_pop_up_event(name: &str, condition: EventCondition, group: PlayerGroup) -> GameEvent {
+    let mut event = pop_up_event(name, condition);
+    event.actions = vec![EventAction::ShowMessage {
+        message: name.to_string(),
+        target: ActionTarget::Players(group),
+    }];
+    event
+}
+
+#[test]
+fn test_engine_event_targeting() {
+    let flow = Flow {
+        links: vec![link(FACTORY, SECOND, 1), link(SECOND, RETAILER, 1)],
+        split: OrderSplit::Equal,
+    };
+    let players_classes = BTreeMap::from([(FACTORY, 0), (SECOND, 0), (RETAILER, 1)]);
+    let engine = start_network_engine(engine_test_settings(), players_classes, flow);
+    let (mut round_state, _) = engine.finish();
+    round_state.team.name = "red".to_string();
+    round_state.users_states.get_mut(&FACTORY).unwrap().money = 50;
+    round_state.users_states.get_mut(&SECOND).unwrap().money = 500;
+    round_state.users_states.get_mut(&RETAILER).unwrap().money = 500;
+    let mut engine = GameEngine::new(round_state);
+    for player in [FACTORY, SECOND, RETAILER] {
+        engine.end_player_round(player, test_order(0)).unwrap();
+    }
+
+    let started = || EventCondition::RoundMet {
+        round: 1,
+        compare: Some(Comparison::GreaterOrEqual),
+    };
+    let within = |players, condition| EventCondition::Within {
+        players,
+        condition: Box::new(condition),
+    };
+    let events = GameEvents {
+        events: vec![
+            group_pop_up_event("class", started(), PlayerGroup::Class(1)),
+            group_pop_up_event(
+                "factory",
+                started(),
+                PlayerGroup::Position(ChainPosition::Factory),
+            ),
+            group_pop_up_event(
+                "upstream",
+                within(PlayerGroup::Position(ChainPosition::Retailer), started()),
+                PlayerGroup::Position(ChainPosition::Upstream),
+            ),
+            group_pop_up_event(
+                "downstream",
+                within(PlayerGroup::Class(0), started()),
+                PlayerGroup::Position(ChainPosition::Downstream),
+            ),
+            group_pop_up_event("red", started(), PlayerGroup::Team("red".to_string())),
+            group_pop_up_event("blue", started(), PlayerGroup::Team("blue".to_string())),
+            pop_up_event(
+                "poor factories",
+                within(
+                    PlayerGroup::Position(ChainPosition::Factory),
+                    EventCondition::ValueExceed {
+                        resource: Resource::Money,
+                        met_by: MetBy::Average,
+                        value: 100,
+                        compare: Some(Comparison::Less),
+                    },
+                ),
+            ),
+        ],
+    };
+    engine.finish_round(&events, None).unwrap();
+
+    let pop_ups: Vec<(Uuid, String)> = engine
+        .finish()
+        .1
+        .into_iter()
+        .filter_map(|msg| match msg {
+            EventMessages::GameEventPopUpUser(player, message) => Some((player, message)),
+            _ => None,
+        })
+        .collect();
+    let expected = vec![
+        (RETAILER, "class".to_string()),
+        (FACTORY, "factory".to_string()),
+        (SECOND, "upstream".to_string()),
+        (RETAILER, "downstream".to_string()),
+        (SECOND, "downstream".to_string()),
+        (FACTORY, "red".to_string()),
+        (RETAILER, "red".to_string()),
+        (SECOND, "red".to_string()),
+        (FACTORY, "poor factories".to_string()),
+    ];
+    assert_eq!(pop_ups, expected);
+}
+
+#[test]
+fn test_engine_event_scheduling() {
+    let always = || EventCondition::RoundMet {
+        round: 0,
+        compare: Some(Comparison::GreaterOrEqual),
+    };
+    let once = GameEvent {
+        run_once: true,
+        ..pop_up_event("once", always())
+    };
+    let every = GameEvent {
+        every: Some(2),
+        from_round: Some(1),
+        ..pop_up_event("every", always())
+    };
+    let window = GameEvent {
+        from_round: Some(2),
+        to_round: Some(3),
+        ..pop_up_event("window", always())
+    };
+    let cooldown = GameEvent {
+        cooldown: Some(1),
+        ..pop_up_event("cooldown", always())
+    };
+    let events = GameEvents {
+        events: vec![once, every, window, cooldown],
+    };
+
+    let fired_names = |messages: Vec<EventMessages>| -> Vec<String> {
+        messages
+            .into_iter()
+            .filter_map(|msg| match msg {
+                EventMessages::GameEventPopUpUser(FACTORY, message) => Some(message),
+                _ => None,
+            })
+            .collect()
+    };
+
+    let (mut round_state, _) = start_test_engine().finish();
+    let mut fired = Vec::new();
+    let mut snapshot = None;
+    for _ in 0..5 {
+        let mut engine = GameEngine::new(round_state);
+        engine.end_player_round(FACTORY, test_order(0)).unwrap();
+        engine.end_player_round(RETAILER, test_order(0)).unwrap();
+        engine.finish_round(&events, None).unwrap();
+        let finished_round = engine.finished_round().unwrap().clone();
+        let messages;
+        (round_state, messages) = engine.finish();
+        fired.push(fired_names(messages));
+        if finished_round.round == 3 {
+            snapshot = Some(finished_round);
+        }
+    }
+    let expected: Vec<Vec<&str>> = vec![
+        vec!["once", "every", "cooldown"],
+        vec!["window"],
+        vec!["every", "window", "cooldown"],
+        vec![],
+        vec!["every", "cooldown"],
+    ];
+    assert_eq!(fired, expected);
+    assert_eq!(round_state.fired_events.get(&1), Some(&vec![1, 3, 5]));
+
+    // the snapshot keeps the events fired in its round, they fire again on rewind
+    let snapshot = snapshot.unwrap();
+    assert_eq!(snapshot.fired_events.get(&2), Some(&vec![2, 3]));
+    let mut engine = GameEngine::new(snapshot);
+    engine.rewind(&events, None).unwrap();
+    let (round_state, messages) = engine.finish();
+    assert_eq!(fired_names(messages), vec!["every", "window", "cooldown"]);
+    assert_eq!(round_state.fired_events.get(&0), Some(&vec![1]));
+    assert_eq!(round_state.fired_events.get(&3), Some(&vec![1, 3]));
+}
+
+fn event_at_round_one(name: &str, action: EventAction) -> GameEvents {
+    GameEvents {
+        events: vec![GameEvent {
+            actions: vec![action],
+            ..pop_up_event(
+                name,
+                EventCondition::RoundMet {
+                    round: 1,
+                    compare: None,
+                },
+            )
+        }],
+    }
+}
+
+/// Plays a round where the retailer orders 3 units.
+fn play_round(
+    round_state: RoundState,
+    events: &GameEvents,
+    factory_order: i64,
+) -> (RoundState, Vec<EventMessages>) {
+    let mut engine = GameEngine::new(round_state);
+    engine
+        .end_player_round(FACTORY, test_order(factory_order))
+        .unwrap();
+    engine.end_player_round(RETAILER, test_order(3)).unwrap();
+    engine.finish_round(events, None).unwrap();
+    engine.finish()
+}
+
+#[test]
+fn test_engine_shipment_disruptions() {
+    let link = ClassLink {
+        sender: 0,
+        recipient: 1,
+    };
+    let last_sent = |round_state: &RoundState| {
+        let factory = round_state.users_states.get(&FACTORY).unwrap();
+        factory.stock.sent_orders.last().unwrap().value
+    };
+    let last_received = |round_state: &RoundState| {
+        let retailer = round_state.users_states.get(&RETAILER).unwrap();
+        retailer.stock.incoming_orders.last().unwrap().value
+    };
+
+    // nothing leaves the factory in round 1, the retailer's order waits as backlog
+    let events = event_at_round_one("strike", EventAction::BlockShipments { link, rounds: 1 });
+    let (round_state, _) = start_test_engine().finish();
+    let (round_state, messages) = play_round(round_state, &events, 0);
+    assert!(messages.contains(&EventMessages::GameEventShipmentsBlocked(link, 1)));
+    assert_eq!(round_state.disruptions.len(), 1);
+
+    let (round_state, _) = play_round(round_state, &events, 0);
+    let factory = round_state.users_states.get(&FACTORY).unwrap();
+    assert_eq!(factory.stock.back_orders.get(&RETAILER), Some(&3));
+    assert_eq!(last_sent(&round_state), 0);
+    assert_eq!(last_received(&round_state), 0);
+    assert!(round_state.disruptions.is_empty());
+
+    let (round_state, _) = play_round(round_state, &events, 0);
+    assert_eq!(last_sent(&round_state), 6);
+    assert_eq!(last_received(&round_state), 6);
+
+    // goods shipped in round 1 are held for 2 more rounds
+    let events = event_at_round_one(
+        "storm",
+        EventAction::DelayShipments {
+            link,
+            delay: 2,
+            rounds: 1,
+        },
+    );
+    let (round_state, _) = start_test_engine().finish();
+    let (round_state, messages) = play_round(round_state, &events, 0);
+    assert!(messages.contains(&EventMessages::GameEventShipmentsDelayed(link, 2, 1)));
+
+    let (round_state, _) = play_round(round_state, &events, 0);
+    assert_eq!(last_sent(&round_state), 3);
+    assert_eq!(last_received(&round_state), 0);
+    assert_eq!(round_state.delayed_shipments.len(), 1);
+    assert_eq!(round_state.delayed_shipments[0].release, 3);
+
+    let (round_state, _) = play_round(round_state, &events, 0);
+    assert_eq!(last_received(&round_state), 3);
+    let (round_state, _) = play_round(round_state, &events, 0);
+    assert_eq!(last_received(&round_state), 6);
+    assert!(round_state.delayed_shipments.is_empty());
+}
+
+#[test]
+fn test_engine_demand_and_price_disruptions() {
+    let last_demand = |round_state: &RoundState| {
+        let retailer = round_state.users_states.get(&RETAILER).unwrap();
+        retailer.stock.requested_orders.last().unwrap().value
+    };
+
+    let mut events = event_at_round_one(
+        "sale",
+        EventAction::ScaleDemand {
+            percent: 50,
+            rounds: 2,
+        },
+    );
+    events.events[0].actions.extend([
+        EventAction::DemandShock { value: 10 },
+        EventAction::ChangePrice {
+            class: 1,
+            product: None,
+            price: 7,
+        },
+    ]);
+
+    // the demand generated for round 1 gets the shock, the next two are halved
+    let (mut round_state, _) = start_test_engine().finish();
+    let mut demands = Vec::new();
+    let mut expected = Vec::new();
+    for percent in [0, 50, 50, 100] {
+        let messages;
+        (round_state, messages) = play_round(round_state, &events, 0);
+        demands.push(last_demand(&round_state));
+        match percent {
+            0 => {
+                assert!(messages.contains(&EventMessages::GameEventDemandShock(10)));
+                expected.push(round_state.demand + 10);
+            }
+            percent => expected.push(round_state.demand * percent / 100),
+        }
+    }
+    assert_eq!(demands, expected);
+    assert_eq!(round_state.settings.resource_price.get(&1), Some(&7));
+
+    let mut settings = engine_test_settings();
+    settings.production_capacity = BTreeMap::from([(0, 5)]);
+    let events = event_at_round_one(
+        "fire",
+        EventAction::CutProductionCapacity {
+            class: 0,
+            percent: 40,
+            rounds: 1,
+        },
+    );
+    let (round_state, _) = start_test_engine_with(settings).finish();
+    let (mut round_state, messages) = play_round(round_state, &events, 8);
+    assert!(messages.contains(&EventMessages::GameEventProductionCut(0, 40, 1)));
+    let mut placed = Vec::new();
+    for _ in 0..2 {
+        let mut engine = GameEngine::new(round_state);
+        engine.end_player_round(FACTORY, test_order(8)).unwrap();
+        placed.push(engine_user_state(&engine, FACTORY).stock.placed_order.value);
+        engine.end_player_round(RETAILER, test_order(3)).unwrap();
+        engine.finish_round(&events, None).unwrap();
+        round_state = engine.finish().0;
+    }
+    assert_eq!(placed, vec![3, 5]);
+
+    let events = event_at_round_one(
+        "unknown",
+        EventAction::ChangePrice {
+            class: 0,
+            product: Some("ice".to_string()),
+            price: 1,
+        },
+    );
+    assert!(events.validate(&engine_test_settings()).is_err());
+    // an action stored before it was validated is skipped, the round still closes
+    let (round_state, messages) = play_round(start_test_engine().finish().0, &events, 0);
+    assert_eq!(round_state.round, 1);
+    assert!(
+        messages.contains(&EventMessages::Error(AppError::BadRequest(
+            "unknown product ice".to_string()
+        )))
+    );
+}
+
+#[test]
+fn test_engine_production_cut_over_full_capacity() {
+    let cut = EventAction::CutProductionCapacity {
+        class: 0,
+        percent: 150,
+        rounds: 3,
+    };
+    assert!(event_at_round_one("fire", cut.clone())
+        .validate(&engine_test_settings())
+        .is_err());
+
+    // a cut stored before it was validated leaves nothing to manufacture
+    let mut settings = engine_test_settings();
+    settings.production_capacity = BTreeMap::from([(0, 10)]);
+    let (mut round_state, _) = start_test_engine_with(settings).finish();
+    round_state.disruptions.push(Disruption {
+        start: round_state.round,
+        action: cut,
+    });
+    for value in [0, 4] {
+        let mut engine = GameEngine::new(round_state.clone());
+        engine.end_player_round(FACTORY, test_order(value)).unwrap();
+        let factory = engine_user_state(&engine, FACTORY);
+        assert_eq!(factory.stock.placed_order.value, 0);
+        assert_eq!(factory.stock.placed_order.cost, 0);
+    }
+}
+
+#[test]
+fn test_engine_patch_settings() {
+    let patch: SettingsPatch = serde_json::from_str(
+        r#"{"resource_price": {"1": 9}, "round_time_limit": 30, "max_rounds": null}"#,
+    )
+    .unwrap();
+    // required fields can't be removed
+    assert!(engine_test_settings().patched(&patch).is_err());
+
+    let patch: SettingsPatch =
+        serde_json::from_str(r#"{"resource_price": {"1": 9}, "round_time_limit": 30}"#).unwrap();
+    let events = event_at_round_one("inflation", EventAction::PatchSettings { patch });
+    let (round_state, _) = start_test_engine().finish();
+    let mut engine = GameEngine::new(round_state);
+    engine.end_player_round(FACTORY, test_order(0)).unwrap();
+    engine.end_player_round(RETAILER, test_order(0)).unwrap();
+    engine.finish_round(&events, None).unwrap();
+
+    let mut expected = engine_test_settings();
+    expected.resource_price.insert(1, 9);
+    expected.round_time_limit = Some(30);
+    // the snapshot keeps the settings its round is played with, a rewind patches the
+    // ones of the round before again
+    let mut snapshot = engine.finished_round().unwrap().clone();
+    assert_eq!(snapshot.settings, expected);
+    let (round_state, messages) = engine.finish();
+    assert_eq!(round_state.settings, expected);
+    assert!(messages.contains(&EventMessages::GameEventSettingsChange(expected.clone())));
+
+    snapshot.settings = engine_test_settings();
+    let mut engine = GameEngine::new(snapshot);
+    engine.rewind(&events, None).unwrap();
+    assert_eq!(engine.finish().0.settings, expected);
+}
+
+#[test]
+fn test_engine_bad_settings_patch() {
+    for patch in [r#"{"max_rounds": "ten"}"#, r#"{"max_rounds": 0}"#] {
+        let patch: SettingsPatch = serde_json::from_str(patch).unwrap();
+        let events = event_at_round_one("broken", EventAction::PatchSettings { patch });
+        assert!(events.validate(&engine_test_settings()).is_err());
+
+        // stored before it was validated, the patch is skipped and the round closes
+        let (round_state, messages) = play_round(start_test_engine().finish().0, &events, 0);
+        assert_eq!(round_state.round, 1);
+        assert_eq!(round_state.settings, engine_test_settings());
+        assert!(messages
+            .iter()
+            .any(|msg| matches!(msg, EventMessages::Error(_))));
+    }
+
+    let mut settings = engine_test_settings();
+    settings.max_rounds = 0;
+    assert!(settings.validate().is_err());
+
+    // a limit lowered below the current round ends the game at the next round end
+    let patch: SettingsPatch = serde_json::from_str(r#"{"max_rounds": 1}"#).unwrap();
+    let events = event_at_round_one("short", EventAction::PatchSettings { patch });
+    let (round_state, _) = play_round(start_test_engine().finish().0, &events, 0);
+    let mut engine = GameEngine::new(round_state);
+    engine.end_player_round(FACTORY, test_order(0)).unwrap();
+    engine.end_player_round(RETAILER, test_order(3)).unwrap();
+    engine.finish_round(&events, None).unwrap();
+    assert!(engine.game_finished());
+}
@@ -17,11 +17,15 @@ use axum::{
 };
 use axum_server::tls_rustls::RustlsConfig;
 use axum_typed_websockets::WebSocketUpgrade;
-use entities::{Flow, GameState, Lobby, Order, Settings, UserState};
+use entities::{
+    DelayedShipment, Disruption, Flow, GameState, Lobby, Order, ProductRound, Settings, Supply,
+    Team, UserState,
+};
 use hyper::{header, Method};
 use lobby::{
+    game::RoundTimers,
     lobby_endpoints::{get_lobbies_endpoint, stop_game_endpoint},
-    stats::{game_stats, players_stats},
+    stats::{game_stats, players_stats, teams_stats},
 };
 use once_cell::sync::Lazy;
 use sqlx::{postgres::PgPoolOptions, PgPool, QueryBuilder};
@@ -43,7 +47,8 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use crate::{
     auth::{authorize_endpoint, Keys},
     lobby::lobby_endpoints::{
-        create_lobby_endpoint, delete_lobby_endpoint, get_lobby_endpoint, start_game_endpoint,
+        create_lobby_endpoint, delete_lobby_endpoint, get_lobby_endpoint, pause_game_endpoint,
+        resume_game_endpoint, rewind_game_endpoint, set_bot_endpoint, start_game_endpoint,
         update_lobby_endpoint,
     },
     template::template::{create_lobby_from_template, create_template_from_lobby_endpoint},
@@ -65,7 +70,39 @@ pub struct LobbyState {
     sender: Arc<sync::broadcast::Sender<EventMessages>>,
     _receiver: Arc<sync::broadcast::Receiver<EventMessages>>,
     started: bool,
-    round_state: RoundState,
+    /// Paused games don't accept orders and their round timers are frozen.
+    paused: bool,
+    /// Classes picked in the lobby before the start, used for players the start
+    /// request gives no class.
+    player_classes: BTreeMap<Uuid, u32>,
+    /// State of every team chain, keyed by team id.
+    teams: BTreeMap<Uuid, RoundState>,
+    /// Round timers of the teams, timers started before a pause are dropped.
+    timers: RoundTimers,
+}
+
+impl LobbyState {
+    pub fn new(
+        sender: sync::broadcast::Sender<EventMessages>,
+        receiver: sync::broadcast::Receiver<EventMessages>,
+    ) -> Self {
+        Self {
+            sender: Arc::new(sender),
+            _receiver: Arc::new(receiver),
+            started: false,
+            paused: false,
+            player_classes: BTreeMap::new(),
+            teams: BTreeMap::new(),
+            timers: RoundTimers::default(),
+        }
+    }
+
+    pub fn team_of(&self, player: &Uuid) -> Option<Uuid> {
+        self.teams
+            .iter()
+            .find(|(_, round_state)| round_state.users_states.contains_key(player))
+            .map(|(team, _)| *team)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -80,23 +117,48 @@ pub struct RoundState {
     settings: Settings,
     flow: Flow,
     demand: i64,
-    supply: i64,
+    supply: Supply,
+    /// Generators of the additional products, by name.
+    products: BTreeMap<String, ProductRound>,
+    team: Team,
+    /// Seed of the random demand and supply, shared by all teams of the game.
+    seed: u64,
+    /// Rounds each game event fired in, by its position in the game events.
+    fired_events: BTreeMap<usize, Vec<i64>>,
+    /// Event actions still lasting, with the round they started in.
+    disruptions: Vec<Disruption>,
+    delayed_shipments: Vec<DelayedShipment>,
 }
 
 impl RoundState {
-    pub fn new() -> Self {
+    /// State of a team restored from its saved round snapshot. The lobby `settings`
+    /// are used for snapshots saved without the settings of their round.
+    pub fn from_snapshot(game_state: GameState, settings: Settings) -> Self {
         Self {
-            round: 0,
-            players: 0,
-            players_finished: 0,
-            users_states: BTreeMap::new(),
-            round_orders: BTreeMap::new(),
-            send_orders: BTreeMap::new(),
-            player_classes: BTreeMap::new(),
-            settings: Settings::default(),
-            flow: Flow::default(),
-            demand: 0,
-            supply: 0,
+            round: game_state.round,
+            players: game_state.players_classes.0.len() as i64,
+            players_finished: game_state.players_classes.0.len() as i64,
+            users_states: game_state.user_states.0,
+            round_orders: game_state.round_orders.0,
+            send_orders: game_state.send_orders.0,
+            player_classes: game_state.players_classes.0,
+            settings: game_state.settings.map_or(settings, |s| s.0),
+            flow: game_state.flow.0,
+            demand: game_state.demand,
+            supply: Supply {
+                capacity: game_state.supply,
+                ordered: game_state.supply_ordered,
+                delivered: game_state.supply_delivered,
+            },
+            products: game_state.products.0,
+            team: Team {
+                id: game_state.team_id,
+                name: game_state.team_name,
+            },
+            seed: game_state.seed as u64,
+            fired_events: game_state.fired_events.0,
+            disruptions: game_state.disruptions.0,
+            delayed_shipments: game_state.delayed_shipments.0,
         }
     }
 }
@@ -213,8 +275,13 @@ pub fn create_app(db: PgPool, state: Arc<State>) -> Router {
         )
         .route("/lobby/:id/start", post(start_game_endpoint))
         .route("/lobby/:id/stop", post(stop_game_endpoint))
+        .route("/lobby/:id/pause", post(pause_game_endpoint))
+        .route("/lobby/:id/resume", post(resume_game_endpoint))
+        .route("/lobby/:id/rewind/:round", post(rewind_game_endpoint))
+        .route("/lobby/:id/bots/:player", put(set_bot_endpoint))
         .route("/lobby/:id/stats/game/", get(game_stats))
         .route("/lobby/:id/stats/players/", get(players_stats))
+        .route("/lobby/:id/stats/teams/", get(teams_stats))
         .route("/lobby/websocket", get(websocket_handler))
         .route(
             "/template",
@@ -255,51 +322,31 @@ async fn restore_lobbies(state: &Arc<State>, db: &PgPool) {
     };
     for lobby in lobbies {
         print!("{}", lobby.id);
+        //TODO: magic number fix
+        let (tx, rx) = sync::broadcast::channel(33);
+        let mut lobby_state = LobbyState::new(tx, rx);
+        lobby_state.paused = lobby.paused;
+
         if lobby.started {
-            let game_state = sqlx::query_as!(GameState,
+            let games_states = sqlx::query_as!(GameState,
                 r#"
-                    select id, round, user_states as "user_states: sqlx::types::Json<BTreeMap<Uuid, UserState>>", round_orders as "round_orders: sqlx::types::Json<BTreeMap<Uuid, Order>>", flow as "flow: sqlx::types::Json<Flow>", demand, supply, send_orders as "send_orders: sqlx::types::Json<BTreeMap<Uuid, Order>>", players_classes as "players_classes: sqlx::types::Json<BTreeMap<Uuid, u32>>", game_id
+                    select distinct on (team_id) id, round, user_states as "user_states: sqlx::types::Json<BTreeMap<Uuid, UserState>>", round_orders as "round_orders: sqlx::types::Json<BTreeMap<Uuid, Order>>", flow as "flow: sqlx::types::Json<Flow>", demand, supply, send_orders as "send_orders: sqlx::types::Json<BTreeMap<Uuid, Order>>", players_classes as "players_classes: sqlx::types::Json<BTreeMap<Uuid, u32>>", game_id, team_id, team_name, seed, supply_ordered, supply_delivered, products as "products: sqlx::types::Json<BTreeMap<String, ProductRound>>", fired_events as "fired_events: sqlx::types::Json<BTreeMap<usize, Vec<i64>>>", disruptions as "disruptions: sqlx::types::Json<Vec<Disruption>>", delayed_shipments as "delayed_shipments: sqlx::types::Json<Vec<DelayedShipment>>", settings as "settings: sqlx::types::Json<Settings>"
                     from "game_state"
-                    where game_id = $1"#,
+                    where game_id = $1
+                    order by team_id, round desc"#,
                     lobby.id,
-            ).fetch_one(db)
+            ).fetch_all(db)
             .await
             .unwrap();
 
-            let (tx, rx) = sync::broadcast::channel(33);
-            state.lobbies.write().await.insert(
-                lobby.id,
-                LobbyState {
-                    sender: Arc::new(tx),
-                    _receiver: Arc::new(rx),
-                    started: false,
-                    round_state: RoundState {
-                        round: game_state.round,
-                        players: game_state.players_classes.0.len() as i64,
-                        players_finished: game_state.players_classes.0.len() as i64,
-                        users_states: game_state.user_states.0,
-                        round_orders: game_state.round_orders.0,
-                        send_orders: game_state.send_orders.0,
-                        player_classes: game_state.players_classes.0,
-                        settings: lobby.settings.0,
-                        flow: game_state.flow.0,
-                        demand: game_state.demand,
-                        supply: game_state.supply,
-                    },
-                },
-            );
-        } else {
-            //TODO: magic number fix
-            let (tx, rx) = sync::broadcast::channel(33);
-            state.lobbies.write().await.insert(
-                lobby.id,
-                LobbyState {
-                    sender: Arc::new(tx),
-                    _receiver: Arc::new(rx),
-                    started: false,
-                    round_state: RoundState::new(),
-                },
-            );
+            for game_state in games_states {
+                lobby_state.teams.insert(
+                    game_state.team_id,
+                    RoundState::from_snapshot(game_state, lobby.settings.0.clone()),
+                );
+            }
         }
+
+        state.lobbies.write().await.insert(lobby.id, lobby_state);
     }
 }
@@ -94,6 +94,9 @@ pub async fn create_template<'a, E>(
 where
     E: Executor<'a, Database = Postgres>,
 {
+    settings.validate()?;
+    events.validate(&settings)?;
+
     let template = sqlx::query_as!(Template,
         // language=PostgreSQL
         r#"insert into "template" (name, max_players, owner_id, settings, events) values ($1, $2, $3, $4, $5) returning id, name, max_players, owner_id, settings as "settings: sqlx::types::Json<Settings>", events as "events: sqlx::types::Json<GameEvents>""#,
@@ -193,6 +196,8 @@ pub async fn update_template_endpoint(
             "can't edit this template".to_string(),
         ));
     }
+    payload.settings.validate()?;
+    payload.events.validate(&payload.settings)?;
 
     let template = sqlx::query_as!(Template,
         // language=PostgreSQL
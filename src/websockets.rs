@@ -7,10 +7,12 @@ use uuid::Uuid;
 
 use crate::{
     auth::{Auth, WebSocketAuth},
-    entities::{Resource, Settings},
+    entities::{BankruptcyOutcome, ClassLink, Resource, Settings},
     error::AppError,
     lobby::{
-        game::{process_user_round_end_message, GameEnd, GameUpdate, UserEndRound},
+        game::{
+            process_user_round_end_message, visible_players, GameEnd, GameUpdate, UserEndRound,
+        },
         lobby::{send_broadcast_msg, update_lobby_classes, LobbyUpdate, LobbyUserUpdate},
     },
     user::user::{disconnect_user, get_user},
@@ -33,16 +35,41 @@ pub enum EventMessages {
     GameEventPopUpAll(String),
     GameEventResourceAddedAll(Resource, i64),
     GameEventResourceAddedUser(Uuid, Resource, i64),
+    /// Player class and its new production capacity.
+    GameEventProductionCapacity(u32, i64),
+    /// Blocked link and for how many rounds.
+    GameEventShipmentsBlocked(ClassLink, i64),
+    /// Delayed link, the delay and for how many rounds.
+    GameEventShipmentsDelayed(ClassLink, i64, i64),
+    /// Percent of the demand and for how many rounds.
+    GameEventDemandScaled(i64, i64),
+    /// Player class, percent cut from its production capacity and for how many rounds.
+    GameEventProductionCut(u32, i64, i64),
+    /// Player class, product and its new price.
+    GameEventPriceChange(u32, Option<String>, i64),
+    /// Units added to the demand.
+    GameEventDemandShock(i64),
+    Bankruptcy(Uuid, BankruptcyOutcome),
     RoundStart(GameUpdate),
+    /// Round number and seconds players have to place their orders.
+    RoundDeadline(i64, u64),
+    /// Players whose orders were placed by the server when time ran out.
+    RoundTimeout(Vec<Uuid>),
     RoundEnd,
     KickAll,
     GameEnd(GameEnd),
+    GamePaused,
+    GameResumed,
+    /// The game was stopped before its last round, results are sent with `GameEnd`.
+    GameAborted,
     UpdateClasses(BTreeMap<Uuid, u32>),
     Ack(Uuid),
     ErrorUser(Uuid, AppError),
     Error(AppError),
     Ping(Vec<u8>),
     Pong(Vec<u8>),
+    /// Message of a single team chain.
+    Team(Uuid, Box<EventMessages>),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -53,12 +80,25 @@ pub enum ServerMessage {
     Error(AppError),
     RoundStart(GameUpdate),
     RoundFinish,
+    RoundDeadline(i64, u64),
+    RoundTimeout(Vec<Uuid>),
     GameStart(GameUpdate),
     GameEventSettingsChange(Settings),
     GameEventPopUp(String),
     GameEventResource(Resource, i64),
+    GameEventProductionCapacity(u32, i64),
+    GameEventShipmentsBlocked(ClassLink, i64),
+    GameEventShipmentsDelayed(ClassLink, i64, i64),
+    GameEventDemandScaled(i64, i64),
+    GameEventProductionCut(u32, i64, i64),
+    GameEventPriceChange(u32, Option<String>, i64),
+    GameEventDemandShock(i64),
+    Bankruptcy(Uuid, BankruptcyOutcome),
     KickAll,
     GameEnd(GameEnd),
+    GamePaused,
+    GameResumed,
+    GameAborted,
     UpdateClasses(BTreeMap<Uuid, u32>),
     Ack,
     Ping(Vec<u8>),
@@ -124,16 +164,62 @@ pub async fn game_process(
         None => todo!(),
     }
 
+    let send_state = state.clone();
     let mut send_task = tokio::spawn(async move {
         while let Ok(event_msg) = rx.recv().await {
+            let event_msg = match event_msg {
+                EventMessages::Team(team, msg) => {
+                    let (user_team, visible) = match send_state.lobbies.read().await.get(&game_id) {
+                        Some(lobby_state) => (
+                            lobby_state.team_of(&user.id),
+                            lobby_state.teams.get(&team).and_then(|round_state| {
+                                visible_players(
+                                    &round_state.settings.visibility,
+                                    &round_state.flow,
+                                    user.id,
+                                )
+                            }),
+                        ),
+                        None => (None, None),
+                    };
+                    // players outside of teams (the owner) see every team
+                    if user_team.is_none() {
+                        *msg
+                    } else if user_team != Some(team) {
+                        continue;
+                    } else {
+                        // the per player parts are cut to the players it sees
+                        match (*msg, visible) {
+                            (EventMessages::GameEnd(ge), Some(visible)) => {
+                                EventMessages::GameEnd(ge.visible_to(&visible))
+                            }
+                            (EventMessages::Bankruptcy(id, _), Some(visible))
+                                if !visible.contains(&id) =>
+                            {
+                                continue
+                            }
+                            (EventMessages::RoundTimeout(mut players), Some(visible)) => {
+                                players.retain(|p| visible.contains(p));
+                                EventMessages::RoundTimeout(players)
+                            }
+                            (msg, _) => msg,
+                        }
+                    }
+                }
+                msg => msg,
+            };
+
             let message = match event_msg {
                 EventMessages::NewUserConnected(l) => ServerMessage::NewUserConnected(l),
                 EventMessages::LobbyUpdate(u) => ServerMessage::LobbyUpdate(u),
                 EventMessages::UserDisconnected(l) => ServerMessage::UserDisconnected(l),
-                EventMessages::GameStart(u) => ServerMessage::GameStart(u),
-                EventMessages::RoundStart(s) => ServerMessage::RoundStart(s),
+                EventMessages::GameStart(u) => ServerMessage::GameStart(u.visible_to(user.id)),
+                EventMessages::RoundStart(s) => ServerMessage::RoundStart(s.visible_to(user.id)),
                 EventMessages::KickAll => ServerMessage::KickAll,
                 EventMessages::GameEnd(ge) => ServerMessage::GameEnd(ge),
+                EventMessages::GamePaused => ServerMessage::GamePaused,
+                EventMessages::GameResumed => ServerMessage::GameResumed,
+                EventMessages::GameAborted => ServerMessage::GameAborted,
                 EventMessages::Ack(id) => {
                     if id != user.id {
                         continue;
@@ -167,10 +253,33 @@ pub async fn game_process(
                     ServerMessage::GameEventPopUp(s)
                 }
                 EventMessages::GameEventPopUpAll(s) => ServerMessage::GameEventPopUp(s),
+                EventMessages::GameEventProductionCapacity(c, v) => {
+                    ServerMessage::GameEventProductionCapacity(c, v)
+                }
+                EventMessages::GameEventShipmentsBlocked(l, r) => {
+                    ServerMessage::GameEventShipmentsBlocked(l, r)
+                }
+                EventMessages::GameEventShipmentsDelayed(l, d, r) => {
+                    ServerMessage::GameEventShipmentsDelayed(l, d, r)
+                }
+                EventMessages::GameEventDemandScaled(p, r) => {
+                    ServerMessage::GameEventDemandScaled(p, r)
+                }
+                EventMessages::GameEventProductionCut(c, p, r) => {
+                    ServerMessage::GameEventProductionCut(c, p, r)
+                }
+                EventMessages::GameEventPriceChange(c, p, v) => {
+                    ServerMessage::GameEventPriceChange(c, p, v)
+                }
+                EventMessages::GameEventDemandShock(v) => ServerMessage::GameEventDemandShock(v),
+                EventMessages::Bankruptcy(id, o) => ServerMessage::Bankruptcy(id, o),
                 EventMessages::RoundEnd => ServerMessage::RoundFinish,
+                EventMessages::RoundDeadline(r, s) => ServerMessage::RoundDeadline(r, s),
+                EventMessages::RoundTimeout(p) => ServerMessage::RoundTimeout(p),
                 EventMessages::UpdateClasses(c) => ServerMessage::UpdateClasses(c),
                 EventMessages::Ping(m) => ServerMessage::Ping(m),
                 EventMessages::Pong(m) => ServerMessage::Pong(m),
+                EventMessages::Team(_, _) => continue,
             };
 
             send_msg(&mut sender, message).await;